@@ -1,7 +1,14 @@
-use anyhow::bail;
+use crate::cmd::measure_cmd::{print_duration, MeasureOptions, Measurement, OutputFormat};
+use crate::cmd::record::{parse_define, FlushPolicy, InputEndPolicy, RecordCmd};
+use crate::file_format::ChildExit;
+use crate::stats::Summary;
+use crate::utils::parse_duration;
+use anyhow::{bail, Context};
 use clap::Parser;
-use std::ffi::OsString;
+use serde_json::json;
+use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
 
 const DEFAULT_RECORDING_DIR: &str = "/tmp/termrec-benchmark";
 
@@ -12,23 +19,49 @@ pub struct BenchmarkCmd {
     #[arg(long, short)]
     input: Option<PathBuf>,
 
+    /// Number of measured runs
     #[arg(long, short = 'n')]
     samples: u32,
 
+    /// Number of runs to perform before the measured runs, their results are discarded
+    #[arg(long, short = 'w', default_value_t = 0)]
+    warmup: u32,
+
+    /// Directory for the recordings of the individual runs (stored in `run_<N>` subdirectories)
     #[clap(long, short = 'd', default_value=DEFAULT_RECORDING_DIR)]
     recording_dir: PathBuf,
 
-    #[clap(long, short = 'f')]
-    from_event: OsString,
+    /// Keep the recordings of the individual runs, instead of deleting them after measuring
+    #[clap(long)]
+    keep: bool,
+
+    /// Width of the terminal in columns [default: 80]
+    #[arg(long)]
+    cols: Option<u16>,
+
+    /// Height of the terminal in rows [default: 24]
+    #[arg(long)]
+    rows: Option<u16>,
 
-    /// Path to a file containing a reference frame to measure up to
-    #[clap(long, short = 't')]
-    to_frame: PathBuf,
+    /// Fail if a barrier of the input is not unlocked in time (e.g. `30s`), the program is killed
+    #[arg(long, value_parser = parse_duration)]
+    barrier_timeout: Option<Duration>,
+
+    /// Define a `${NAME}` variable of the input (NAME=VALUE)
+    #[arg(long, short = 'D', value_parser = parse_define)]
+    define: Vec<(String, String)>,
+
+    #[clap(flatten)]
+    measure: MeasureOptions,
 
     /// Print the timestamp in automatically selected human units, otherwise always uses microseconds
     #[clap(long, short = 'u')]
     human_units: bool,
 
+    /// Output format
+    #[clap(long, value_enum, default_value_t)]
+    format: OutputFormat,
+
     // The command and arguments to benchmark
     #[clap(required = true)]
     command: Vec<String>,
@@ -36,33 +69,89 @@ pub struct BenchmarkCmd {
 
 impl BenchmarkCmd {
     pub fn run(self) -> anyhow::Result<()> {
-        bail!("Not implemented");
-        /*
-               if self.recording_dir.exists() {
-                   bail!("Tmp directory ({DEFAULT_RECORDING_DIR}) exists, consider removing it or use a different dir. ")
-               }
-
-               let event_from = parse_event_cmdline(&self.from_event)?;
-               let frame_to =
-                   fs::read(self.to_frame).context("Failed to read reference frame (--frame_to)")?;
-
-               for _ in 0..self.samples {
-                   RecordCmd {
-                       input: self.input.clone(),
-                       output: None,
-                       output_dir: Some(self.recording_dir.clone()),
-                       command: self.command.clone(),
-                   }
-                   .run()?;
-
-                   let delta = measure(&frame_to[..], &event_from, &self.recording_dir)?;
-                   println!("{delta:?}");
-
-                   fs::remove_dir_all(&self.recording_dir)
-                       .context("Failed to delete recording tmp directory")?
-               }
-
-               Ok(())
-        */
+        fs::create_dir_all(&self.recording_dir).context("Failed to create recording directory")?;
+
+        let mut samples = Vec::new();
+        let mut runs_json = Vec::new();
+        for run in 0..self.warmup + self.samples {
+            let warmup = run < self.warmup;
+            let run_name = if warmup {
+                format!("warmup_{run}")
+            } else {
+                format!("run_{}", run - self.warmup)
+            };
+            let run_dir = self.recording_dir.join(&run_name);
+            // Left over from a previous benchmark with --keep
+            if run_dir.exists() {
+                fs::remove_dir_all(&run_dir)
+                    .with_context(|| format!("Failed to delete {}", run_dir.display()))?;
+            }
+
+            log::info!("Starting {run_name}");
+            let child_exit = RecordCmd {
+                input: self.input.clone(),
+                interactive: false,
+                verbose: false,
+                child_stderr: None,
+                output: None,
+                output_dir: Some(run_dir.clone()),
+                cols: self.cols,
+                rows: self.rows,
+                pixel_width: None,
+                pixel_height: None,
+                // Avoid disk writes while the program is being measured
+                flush: FlushPolicy::Exit,
+                barrier_timeout: self.barrier_timeout,
+                kill_on_barrier_timeout: true,
+                on_input_end: InputEndPolicy::Wait,
+                max_duration: None,
+                define: self.define.clone(),
+                command: self.command.clone(),
+            }
+            .record()
+            .with_context(|| format!("Failed to record {run_name}"))?;
+
+            if child_exit != ChildExit::Exited(0) {
+                bail!("Benchmarked program terminated in {run_name}: {child_exit:?}");
+            }
+
+            if !warmup {
+                let measurements = self
+                    .measure
+                    .measure(&run_dir)
+                    .with_context(|| format!("Failed to measure {run_name}"))?;
+                if let OutputFormat::Json = self.format {
+                    runs_json.push(json!({
+                        "run": run_name,
+                        "measurements": self.measure.measurements_to_json(&measurements),
+                    }));
+                } else {
+                    for measurement in &measurements {
+                        print_duration(measurement.delta(), self.human_units);
+                    }
+                }
+                samples.extend(measurements.iter().map(Measurement::delta));
+            }
+
+            if !self.keep {
+                fs::remove_dir_all(&run_dir).context("Failed to delete recording tmp directory")?;
+            }
+        }
+
+        let summary = Summary::from_samples(&samples).context("No measurements were made")?;
+        if let OutputFormat::Json = self.format {
+            let output = json!({
+                "runs": runs_json,
+                "summary": summary.to_json(),
+            });
+            println!("{output:#}");
+        } else if self.human_units {
+            println!();
+            print!("{}", summary.human_units());
+        } else {
+            println!();
+            print!("{}", summary.microseconds());
+        }
+        Ok(())
     }
 }
@@ -1,8 +1,9 @@
 use crate::event::EventFile;
-use crate::file_format::{filter_output_events, load_recording};
+use crate::file_format::RecordingReader;
 use crate::unbuffered_stdout::UnbufferedStdout;
 use anyhow::Context;
 use clap::Parser;
+use std::io::Write;
 use std::path::PathBuf;
 
 /// Play the recording frame-by-frame (to be processed programatically)
@@ -19,14 +20,16 @@ pub struct ControlledPlayCmd {
 
 impl ControlledPlayCmd {
     pub fn run(self) -> anyhow::Result<()> {
-        let recording = load_recording(&self.recording).context("Failed to load recording")?;
-        let events = filter_output_events(recording);
+        let events = RecordingReader::open(&self.recording)
+            .context("Failed to load recording")?
+            .output_events();
 
         let mut write_event = EventFile::connect(self.write_event)?;
         let mut finished_event = EventFile::connect(self.finished_event)?;
 
         let mut stdout = UnbufferedStdout::lock();
-        for (_, data) in events {
+        for event in events {
+            let (_, data) = event.context("Failed to load recording")?;
             write_event.wait()?;
             stdout
                 .write_all(&data)
@@ -0,0 +1,61 @@
+use crate::file_format::{
+    load_recording_with_metadata, save_recording_asciicast, save_recording_script,
+    save_recording_ttyrec,
+};
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    /// asciicast v2, the format of asciinema
+    Asciicast,
+    /// ttyrec, the format of ttyrec/ttyplay (only the output)
+    Ttyrec,
+    /// Typescript and classic timing file of util-linux script (only the output)
+    Script,
+    /// Typescript and advanced timing file of util-linux script, with the input in the same
+    /// file as the output (replay with `scriptreplay --log-io`)
+    ScriptAdvanced,
+}
+
+/// Convert a recording to a different format
+#[derive(Parser)]
+pub struct ExportCmd {
+    #[arg(long, value_enum)]
+    format: ExportFormat,
+
+    /// Output file to save the converted recording to (the typescript for script)
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Output file to save the timing of script to
+    #[arg(
+        long,
+        required_if_eq_any([("format", "script"), ("format", "script-advanced")])
+    )]
+    timing: Option<PathBuf>,
+
+    recording: PathBuf,
+}
+
+impl ExportCmd {
+    pub fn run(self) -> anyhow::Result<()> {
+        let (metadata, recording) =
+            load_recording_with_metadata(&self.recording).context("Failed to load recording")?;
+        match self.format {
+            ExportFormat::Asciicast => {
+                save_recording_asciicast(&metadata, &recording, &self.output)
+            }
+            ExportFormat::Ttyrec => save_recording_ttyrec(&metadata, &recording, &self.output),
+            ExportFormat::Script | ExportFormat::ScriptAdvanced => save_recording_script(
+                &metadata,
+                &recording,
+                self.timing.as_deref().unwrap(),
+                &self.output,
+                self.format == ExportFormat::ScriptAdvanced,
+            ),
+        }
+        .context("Failed to save recording")
+    }
+}
@@ -0,0 +1,76 @@
+use crate::file_format::{
+    load_recording, save_input_termrec, Barrier, InputEvent, RecordingEvent, SignalEvent,
+    SimulationEvent,
+};
+use anyhow::Context;
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Create an input simulation file from the input events of a recording
+#[derive(Parser)]
+pub struct ExtractInputCmd {
+    /// Output file to save the input simulation to
+    #[arg(short, long)]
+    output: PathBuf,
+
+    recording: PathBuf,
+}
+
+impl ExtractInputCmd {
+    pub fn run(self) -> anyhow::Result<()> {
+        let recording = load_recording(&self.recording).context("Failed to load recording")?;
+        let events = recording_to_simulation_events(recording);
+        save_input_termrec(&events, &self.output).context("Failed to save input")?;
+        Ok(())
+    }
+}
+
+fn recording_to_simulation_events(
+    recording: Vec<(Duration, RecordingEvent)>,
+) -> Vec<SimulationEvent> {
+    let mut events = Vec::new();
+    // Input timestamps are relative to the last barrier unlock or finished sleep
+    let mut segment_start = Duration::from_secs(0);
+
+    for (index, (timestamp, event)) in recording.into_iter().enumerate() {
+        match event {
+            RecordingEvent::InputRealized(data) => {
+                events.push(SimulationEvent::Input(InputEvent {
+                    timestamp: timestamp.saturating_sub(segment_start),
+                    data,
+                }))
+            }
+            // A barrier that timed out is kept, so the input can be used to record again
+            RecordingEvent::BarrierUnlocked(description)
+            | RecordingEvent::BarrierTimedOut(description) => {
+                match Barrier::from_description(&description) {
+                    Ok(barrier) => events.push(SimulationEvent::WaitBarrier {
+                        barrier,
+                        timeout: None,
+                    }),
+                    Err(e) => log::warn!("Skipping invalid barrier event: {e:#}"),
+                }
+                segment_start = timestamp;
+            }
+            RecordingEvent::SignalSent(description) => {
+                let timestamp = timestamp.saturating_sub(segment_start);
+                match SignalEvent::from_description(timestamp, &description) {
+                    Ok(event) => events.push(SimulationEvent::Signal(event)),
+                    Err(e) => log::warn!("Skipping invalid signal event: {e:#}"),
+                }
+            }
+            RecordingEvent::SleepFinished(duration) => {
+                events.push(SimulationEvent::Sleep(duration));
+                segment_start = timestamp;
+            }
+            RecordingEvent::Marker(data) => events.push(SimulationEvent::Marker(data)),
+            // The initial terminal size is not part of the input, it is set by `record` instead
+            RecordingEvent::Resize(_) if index == 0 => (),
+            RecordingEvent::Resize(size) => events.push(SimulationEvent::Resize(size)),
+            RecordingEvent::Output(_) | RecordingEvent::Stopped(_) | RecordingEvent::Exit(_) => (),
+        }
+    }
+
+    events
+}
@@ -0,0 +1,50 @@
+use crate::file_format::{load_recording_script, load_recording_ttyrec, save_recording_termrec};
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ImportFormat {
+    /// ttyrec, the format of ttyrec/ttyplay
+    Ttyrec,
+    /// Typescript and timing file of util-linux script (both classic and advanced timing format)
+    Script,
+}
+
+/// Convert a recording made by a different program to a termrec recording
+#[derive(Parser)]
+pub struct ImportCmd {
+    #[arg(long, value_enum)]
+    format: ImportFormat,
+
+    /// Timing file of script (--log-timing)
+    #[arg(long, required_if_eq("format", "script"))]
+    timing: Option<PathBuf>,
+
+    /// Input log of script, when the input was logged to a separate file (--log-in)
+    #[arg(long)]
+    input_log: Option<PathBuf>,
+
+    /// Output file to save the recording to
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// The recording (the typescript/--log-out/--log-io file for script)
+    recording: PathBuf,
+}
+
+impl ImportCmd {
+    pub fn run(self) -> anyhow::Result<()> {
+        let (metadata, recording) = match self.format {
+            ImportFormat::Ttyrec => load_recording_ttyrec(&self.recording),
+            ImportFormat::Script => load_recording_script(
+                self.timing.as_deref().unwrap(),
+                &self.recording,
+                self.input_log.as_deref(),
+            ),
+        }
+        .context("Failed to load recording")?;
+        save_recording_termrec(&metadata, &recording, &self.output)
+            .context("Failed to save recording")
+    }
+}
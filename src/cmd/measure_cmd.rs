@@ -1,8 +1,10 @@
-use crate::file_format::{load_recording, parse_event_cmdline, RecordingEvent};
+use crate::file_format::{parse_event_cmdline, RecordingReader};
+use crate::stats::Summary;
 use crate::utils::{delete_subslices, find_subslice};
 use anyhow::{bail, Context};
 use clap::ArgGroup;
-use clap::Parser;
+use clap::{Args, Parser, ValueEnum};
+use serde_json::json;
 use std::ffi::OsString;
 use std::fs;
 use std::os::unix::ffi::OsStrExt;
@@ -10,16 +12,56 @@ use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 #[derive(Parser)]
-#[command(group(
-    ArgGroup::new("flags")
-        .args(&["to_frame", "to_frame_with_text", "to_event"])
-        .required(true)
-))]
 /// Measure time between events in a recording
 pub struct MeasureCmd {
     #[clap(long, short = 'd')]
     recording_dir: PathBuf,
 
+    #[clap(flatten)]
+    options: MeasureOptions,
+
+    /// Print the timestamp in automatically selected human units, otherwise always uses microseconds
+    #[clap(long, short = 'u')]
+    human_units: bool,
+
+    /// Also print the individual measurements (requires --all)
+    #[clap(long, requires = "all")]
+    list: bool,
+
+    /// Output format
+    #[clap(long, value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    /// Print the individual measurements and the statistics as a JSON object
+    Json,
+}
+
+/// A single measurement, the timestamps are relative to the start of the recording
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Measurement {
+    pub from: Duration,
+    pub to: Duration,
+}
+
+impl Measurement {
+    pub fn delta(&self) -> Duration {
+        self.to - self.from
+    }
+}
+
+/// What to measure in a recording, shared by `measure` and `benchmark`
+#[derive(Args)]
+#[command(group(
+    ArgGroup::new("flags")
+        .args(&["to_frame", "to_frame_with_text", "to_event"])
+        .required(true)
+))]
+pub struct MeasureOptions {
     // Only search for from_event and to_frame/to_event before this event
     #[clap(long)]
     before_event: Option<OsString>,
@@ -29,7 +71,7 @@ pub struct MeasureCmd {
     after_event: Option<OsString>,
 
     // The event to measure time from
-    #[clap(long)]
+    #[clap(long, short = 'f')]
     from_event: OsString,
 
     /// When comparing frames, ignore the following character sequences
@@ -38,7 +80,7 @@ pub struct MeasureCmd {
     ignore_sequence: Vec<OsString>,
 
     /// Path to a file containing a reference frame to measure up to
-    #[clap(long)]
+    #[clap(long, short = 't')]
     to_frame: Option<PathBuf>,
 
     /// Search for a frame containing text
@@ -49,18 +91,62 @@ pub struct MeasureCmd {
     #[clap(long)]
     to_event: Option<OsString>,
 
-    /// Print the timestamp in automatically selected human units, otherwise always uses microseconds
-    #[clap(long, short = 'u')]
-    human_units: bool,
+    /// Measure every occurrence of --from-event (each paired with the next matching frame/event)
+    /// and print statistics of the measurements
+    #[clap(long, short = 'a')]
+    pub all: bool,
 }
 
 impl MeasureCmd {
     pub fn run(self) -> anyhow::Result<()> {
-        let recording = load_recording(&self.recording_dir.join("recording.termrec"))
+        let measurements = self.options.measure(&self.recording_dir)?;
+        let deltas: Vec<Duration> = measurements.iter().map(Measurement::delta).collect();
+
+        if let OutputFormat::Json = self.format {
+            let output = json!({
+                "measurements": self.options.measurements_to_json(&measurements),
+                "summary": Summary::from_samples(&deltas).map(|summary| summary.to_json()),
+            });
+            println!("{output:#}");
+        } else if self.options.all {
+            let summary = Summary::from_samples(&deltas)
+                .context("Didn't find any --from-event followed by --to-event/--to-frame")?;
+
+            if self.list {
+                for delta in &deltas {
+                    print_duration(*delta, self.human_units);
+                }
+            }
+            if self.human_units {
+                print!("{}", summary.human_units());
+            } else {
+                print!("{}", summary.microseconds());
+            }
+        } else {
+            print_duration(deltas[0], self.human_units);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn print_duration(duration: Duration, human_units: bool) {
+    if human_units {
+        println!("{duration:?}")
+    } else {
+        println!("{duration}", duration = duration.as_micros())
+    }
+}
+
+impl MeasureOptions {
+    /// Measures the recording in `recording_dir`. Returns a single measurement, or every
+    /// measurement found with --all (possibly none).
+    pub fn measure(&self, recording_dir: &Path) -> anyhow::Result<Vec<Measurement>> {
+        let recording = RecordingReader::open(&recording_dir.join("recording.termrec"))
             .context("Failed to load recording")?;
 
         let after_event = self
-            .before_event
+            .after_event
             .as_deref()
             .map(parse_event_cmdline)
             .transpose()
@@ -78,154 +164,164 @@ impl MeasureCmd {
 
         let from_event = parse_event_cmdline(&self.from_event).context("Invalid --from-event")?;
 
-        let recording = filter_only_after_and_before_events(recording, after_event, before_event);
+        let to_event = self
+            .to_event
+            .as_deref()
+            .map(parse_event_cmdline)
+            .transpose()
+            .context("Invalid --to-event")?;
+
+        #[allow(clippy::type_complexity)]
+        let matches: Box<dyn Fn(&[u8]) -> bool> = if let Some(to_frame) = &self.to_frame {
+            let reference_frame =
+                fs::read(to_frame).context("Specified `to_frame` file does not exist.")?;
+
+            Box::new(move |frame_contents| {
+                reference_frame == delete_subslices(frame_contents, &ignore_sequences)[..]
+            })
+        } else if let Some(data) = &self.to_frame_with_text {
+            Box::new(move |frame_contents| {
+                find_subslice(
+                    &delete_subslices(frame_contents, &ignore_sequences),
+                    data.as_bytes(),
+                )
+                .is_some()
+            })
+        } else {
+            Box::new(|_| false)
+        };
 
-        let delta;
-        if let Some(to_event) = self.to_event {
-            let to_event = parse_event_cmdline(&to_event).context("Invalid --to-event")?;
+        let mut in_range = after_event.is_none();
+        let mut from_timestamps = Vec::new();
+        let mut to_timestamps = Vec::new();
+        let mut last_checked_frame = None;
 
-            let mut start = None;
-            let mut end = None;
+        for event in recording {
+            let (timestamp, event) = event.context("Failed to load recording")?;
 
-            for (timestamp, event) in recording {
-                if event == from_event {
-                    if let Some(start) = start {
-                        log::warn!("Found multiple --from-event: {:?} and {:?}", start, event);
-                    }
-                    start = Some(timestamp);
-                }
-                if event == to_event {
-                    end = Some(timestamp);
+            if after_event.as_ref() == Some(&event) {
+                in_range = true;
+                continue; // skip after_event itself
+            }
+            if before_event.as_ref() == Some(&event) {
+                if in_range {
                     break;
+                } else {
+                    continue;
                 }
             }
+            if !in_range {
+                continue;
+            }
 
-            delta = end.context("Didn't find --to_event")?
-                - start.context("Didn't find --from_event")?
-        } else
-        /* to_frame/to_frame_with text */
-        {
-            #[allow(clippy::type_complexity)]
-            let matches: Box<dyn Fn(&[u8]) -> bool> = if let Some(to_frame) = self.to_frame {
-                let reference_frame =
-                    fs::read(to_frame).context("Specified `to_frame` file does not exist.")?;
+            if event == from_event {
+                match from_timestamps.last() {
+                    Some(previous) if !self.all && to_event.is_some() => {
+                        log::warn!("Found multiple --from-event: at {previous:?} and {timestamp:?}")
+                    }
+                    _ => (),
+                }
+                from_timestamps.push(timestamp);
+            }
 
-                Box::new(move |frame_contents| {
-                    reference_frame == delete_subslices(frame_contents, &ignore_sequences)[..]
-                })
-            } else if let Some(data) = self.to_frame_with_text {
-                Box::new(move |frame_contents| {
-                    find_subslice(
-                        &delete_subslices(frame_contents, &ignore_sequences),
-                        data.as_bytes(),
-                    )
-                    .is_some()
-                })
+            let is_to = if let Some(to_event) = &to_event {
+                event == *to_event
+            } else if (self.all || to_timestamps.is_empty())
+                && last_checked_frame != Some(timestamp)
+            {
+                // Reading the frames is the expensive part, check each one only once
+                last_checked_frame = Some(timestamp);
+                read_frame(recording_dir, timestamp)?.is_some_and(|contents| matches(&contents))
             } else {
-                unreachable!()
+                false
             };
+            if is_to {
+                to_timestamps.push(timestamp);
+            }
 
-            delta = measure(&matches, &from_event, &recording, &self.recording_dir)?
-        };
-
-        if self.human_units {
-            println!("{delta:?}")
-        } else {
-            println!("{delta}", delta = delta.as_micros())
+            // Without --all, the rest of the recording doesn't need to be read once the
+            // measurement is decided
+            if !self.all
+                && !to_timestamps.is_empty()
+                && (to_event.is_some() || !from_timestamps.is_empty())
+            {
+                break;
+            }
         }
 
-        Ok(())
-    }
-}
-
-pub fn measure(
-    frame_matches: &impl Fn(&[u8]) -> bool,
-    from_event: &RecordingEvent,
-    recording: &[(Duration, RecordingEvent)],
-    recording_dir: &Path,
-) -> anyhow::Result<Duration> {
-    let timestamp_from =
-        find_event_time(from_event, recording).context("Didn't find --from-event")?;
-    let timestamp_to = find_timestamp_of_frame(frame_matches, recording, recording_dir)
-        .context("Didn't find --to-frame")?;
-
-    if timestamp_to < timestamp_from {
-        bail!(
-            "Event happened at {timestamp_from:?}, but frame appeared sooner at {timestamp_to:?}."
-        );
-    }
-
-    let delta = timestamp_to - timestamp_from;
-    Ok(delta)
-}
-
-fn filter_only_after_and_before_events(
-    events: Vec<(Duration, RecordingEvent)>,
-    after_event: Option<RecordingEvent>,
-    before_event: Option<RecordingEvent>,
-) -> Vec<(Duration, RecordingEvent)> {
-    if after_event.is_none() && before_event.is_none() {
-        return events;
-    }
-    let mut result = Vec::new();
-    let mut in_range = after_event.is_none();
-
-    for (timestamp, event) in events {
-        if after_event
-            .as_ref()
-            .is_some_and(|after_event| after_event == &event)
-        {
-            in_range = true;
-            continue; // skip after_event itself
+        if self.all {
+            return Ok(measure_all(&from_timestamps, &to_timestamps));
         }
 
-        if before_event
-            .as_ref()
-            .is_some_and(|before_event| before_event == &event)
+        let measurement = if to_event.is_some() {
+            let from = *from_timestamps.last().context("Didn't find --from_event")?;
+            let to = *to_timestamps.first().context("Didn't find --to_event")?;
+            if to < from {
+                bail!("--from-event happened at {from:?}, but --to-event sooner at {to:?}.");
+            }
+            Measurement { from, to }
+        } else
+        /* to_frame/to_frame_with text */
         {
-            if in_range {
-                break;
-            } else {
-                continue;
+            let from = *from_timestamps
+                .first()
+                .context("Didn't find --from-event")?;
+            let to = *to_timestamps.first().context("Didn't find --to-frame")?;
+            if to < from {
+                bail!("Event happened at {from:?}, but frame appeared sooner at {to:?}.");
             }
-        }
+            Measurement { from, to }
+        };
 
-        if in_range {
-            result.push((timestamp, event));
-        }
+        Ok(vec![measurement])
     }
 
-    result
+    /// Describes each measurement, including which event and frame it was measured between
+    pub fn measurements_to_json(&self, measurements: &[Measurement]) -> serde_json::Value {
+        let to_event = self.to_event.as_ref().map(|e| e.to_string_lossy());
+        measurements
+            .iter()
+            .map(|measurement| {
+                json!({
+                    "from_event": self.from_event.to_string_lossy(),
+                    "from_us": measurement.from.as_micros() as u64,
+                    "to_event": to_event,
+                    "to_frame": to_event.is_none().then(|| frame_filename(measurement.to)),
+                    "to_us": measurement.to.as_micros() as u64,
+                    "delta_us": measurement.delta().as_micros() as u64,
+                })
+            })
+            .collect()
+    }
 }
 
-fn find_event_time(
-    reference_event: &RecordingEvent,
-    recording: &[(Duration, RecordingEvent)],
-) -> Option<Duration> {
-    recording
+/// Pairs every `from` timestamp with the first `to` timestamp that is not sooner. Both lists have
+/// to be sorted.
+fn measure_all(from_timestamps: &[Duration], to_timestamps: &[Duration]) -> Vec<Measurement> {
+    from_timestamps
         .iter()
-        .find(|(_timestamp, recording_event)| reference_event == recording_event)
-        .map(|(timestamp, _)| *timestamp)
+        .filter_map(|&from| {
+            let index = to_timestamps.partition_point(|&to| to < from);
+            match to_timestamps.get(index) {
+                Some(&to) => Some(Measurement { from, to }),
+                None => {
+                    log::warn!("No match found for --from-event at {from:?}");
+                    None
+                }
+            }
+        })
+        .collect()
 }
 
-fn find_timestamp_of_frame(
-    frame_matches: &impl Fn(&[u8]) -> bool,
-    recording: &[(Duration, RecordingEvent)],
-    frames_dir: &Path,
-) -> anyhow::Result<Duration> {
-    for (timestamp, _event) in recording {
-        let filename = format!("frame_{}", timestamp.as_micros());
-
-        let file_contents = match fs::read(frames_dir.join(&filename)) {
-            Ok(contents) => contents,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
-            Err(e) => Err(e).context("Failed to read frame: {filename}")?,
-        };
+fn frame_filename(timestamp: Duration) -> String {
+    format!("frame_{}", timestamp.as_micros())
+}
 
-        if frame_matches(&file_contents) {
-            return Ok(*timestamp);
-        }
+fn read_frame(frames_dir: &Path, timestamp: Duration) -> anyhow::Result<Option<Vec<u8>>> {
+    let filename = frame_filename(timestamp);
+    match fs::read(frames_dir.join(&filename)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context(format!("Failed to read frame: {filename}")),
     }
-
-    bail!("Frame not found in directory");
 }
@@ -1,5 +1,8 @@
 pub mod benchmark;
 pub mod controlled_play;
+pub mod export;
+pub mod extract_input;
+pub mod import;
 pub mod measure_cmd;
 pub mod play;
 pub mod record;
@@ -1,45 +1,667 @@
-use crate::file_format::{filter_output_events, load_recording, Data};
+use crate::file_format::{Data, RecordingEvent, RecordingReader};
+use crate::terminal::{get_terminal_size, RawMode};
 use crate::unbuffered_stdout::UnbufferedStdout;
-use anyhow::{bail, Context};
+use crate::utils::parse_duration;
+use anyhow::{bail, ensure, Context};
 use clap::Parser;
-use std::path::PathBuf;
-use std::thread;
-use std::time::{Duration, SystemTime};
+use nix::errno::Errno;
+use nix::sys::select::{select, FdSet};
+use nix::sys::time::{TimeVal, TimeValLike};
+use nix::unistd::read;
+use std::io::{stdin, IsTerminal, Stdin, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 /// Replay a saved termrec recording
+///
+/// When stdin is a terminal the playback can be controlled using the keyboard: space pauses and
+/// resumes, `.` steps one output event, `]`/`[` jump to the next/previous marker or barrier, `+`/`-`
+/// change the speed and `q` quits.
 #[derive(Parser)]
 pub struct PlayCmd {
     #[clap(short, long, default_value_t = 1000)] //1ms
     max_accuracy_delta_us: u64,
+
+    /// Playback speed, e.g. 2 plays twice as fast (from 1/64 to 64)
+    #[clap(long, default_value_t = 1.0, value_parser = parse_speed)]
+    speed: f64,
+
+    /// Shorten pauses between output longer than this duration (e.g. `2s`, `500ms`)
+    #[clap(long, value_parser = parse_duration)]
+    idle_time_limit: Option<Duration>,
+
+    /// Start the playback at a timestamp (e.g. `90s`, `1m30s`) or at a marker (`marker:NAME`, or
+    /// just the name if it isn't a timestamp), the output before it is written immediately
+    #[clap(long, value_parser = parse_position)]
+    start_at: Option<PlaybackPosition>,
+
+    /// Stop the playback at a timestamp or at a marker, in the same format as --start-at
+    #[clap(long, value_parser = parse_position)]
+    stop_at: Option<PlaybackPosition>,
+
+    /// Don't read keyboard controls from stdin
+    #[clap(long)]
+    no_controls: bool,
+
+    /// Show the position and speed on the last row while paused. It is drawn over the output and
+    /// uses the terminal's saved cursor (`ESC 7`), the row is cleared when the playback resumes.
+    #[clap(long)]
+    status: bool,
+
     recording: PathBuf,
 }
 
+#[derive(Clone, Debug)]
+enum PlaybackPosition {
+    Timestamp(Duration),
+    Marker(String),
+}
+
+impl PlaybackPosition {
+    fn reached(&self, timestamp: Duration, event: &RecordingEvent) -> bool {
+        match (self, event) {
+            (PlaybackPosition::Timestamp(position), _) => timestamp >= *position,
+            (PlaybackPosition::Marker(name), RecordingEvent::Marker(marker)) => {
+                name.as_bytes() == &marker[..]
+            }
+            (PlaybackPosition::Marker(_), _) => false,
+        }
+    }
+}
+
+fn parse_position(s: &str) -> anyhow::Result<PlaybackPosition> {
+    if let Some(name) = s.strip_prefix("marker:") {
+        ensure!(!name.is_empty(), "Missing marker name");
+        return Ok(PlaybackPosition::Marker(name.to_string()));
+    }
+    Ok(match parse_duration(s) {
+        Ok(timestamp) => PlaybackPosition::Timestamp(timestamp),
+        Err(_) => PlaybackPosition::Marker(s.to_string()),
+    })
+}
+
+/// The speed is limited, the clock computations overflow at extreme speeds
+const MIN_SPEED: f64 = 1.0 / 64.0;
+const MAX_SPEED: f64 = 64.0;
+
+fn parse_speed(s: &str) -> anyhow::Result<f64> {
+    let speed: f64 = s.parse().context("Invalid number")?;
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        bail!("Speed has to be between 1/64 and 64");
+    }
+    Ok(speed)
+}
+
 impl PlayCmd {
     pub fn run(self) -> anyhow::Result<()> {
-        let recording = load_recording(&self.recording).context("Failed to load recording")?;
-        let events: Vec<(Duration, Data)> = filter_output_events(recording);
-        let max_delta = Duration::from_micros(self.max_accuracy_delta_us);
-
-        let mut stdout = UnbufferedStdout::lock();
-        let mut last_timestamp = Duration::from_secs(0);
-
-        for (timestamp, data) in events {
-            let begin = SystemTime::now();
-            if timestamp >= last_timestamp {
-                let delta = timestamp - last_timestamp;
-                thread::sleep(delta);
-            } else {
-                let delta = last_timestamp - timestamp;
-                if delta > max_delta {
-                    bail!(
-                        "Playback too slow: maximum delta {max_delta:?}, actual delta: {delta:?}"
-                    );
+        let controls = if !self.no_controls && stdin().is_terminal() {
+            Some(Controls::enable(self.status)?)
+        } else {
+            None
+        };
+        let mut player = Player::new(&self, controls, UnbufferedStdout::lock())?;
+
+        if let Some(start_at) = &self.start_at {
+            if !player.fast_forward(|_, timestamp, event| start_at.reached(timestamp, event))? {
+                log::warn!("--start-at position not found in the recording");
+            }
+            if let PlaybackPosition::Timestamp(position) = start_at {
+                player.previous_output = *position;
+            }
+            player.reanchor();
+        }
+        player.play()
+    }
+}
+
+/// Keyboard controls and the status line, only used when stdin is a terminal
+struct Controls {
+    _raw_mode: RawMode,
+    stdin: Stdin,
+    /// Draw the status line while paused
+    status: bool,
+    /// The status line is drawn over the last row
+    status_shown: bool,
+}
+
+impl Controls {
+    fn enable(status: bool) -> anyhow::Result<Self> {
+        Ok(Self {
+            _raw_mode: RawMode::enable()?,
+            stdin: stdin(),
+            status,
+            status_shown: false,
+        })
+    }
+
+    /// Waits until a key is pressed or the timeout expires (None waits indefinitely)
+    fn read_keys(&mut self, timeout: Option<Duration>) -> anyhow::Result<Vec<u8>> {
+        let stdin_fd = self.stdin.as_fd();
+        let mut rfds = FdSet::new();
+        rfds.insert(stdin_fd);
+        let mut timeout = timeout.map(|t| TimeVal::microseconds(t.as_micros() as i64));
+        match select(None, &mut rfds, None, None, timeout.as_mut()) {
+            Ok(_) => (),
+            Err(Errno::EINTR) => return Ok(Vec::new()),
+            Err(e) => Err(e).context("select on stdin")?,
+        }
+        if !rfds.contains(stdin_fd) {
+            return Ok(Vec::new());
+        }
+
+        let mut buffer = [0u8; 64];
+        match read(stdin_fd.as_raw_fd(), &mut buffer) {
+            Ok(0) => bail!("stdin closed"),
+            Ok(n) => Ok(buffer[..n].to_vec()),
+            Err(Errno::EAGAIN) | Err(Errno::EINTR) => Ok(Vec::new()),
+            Err(e) => Err(e).context("read from stdin"),
+        }
+    }
+
+    /// Draws the status line over the last row of the terminal, restoring the cursor afterwards
+    fn draw_status(
+        &mut self,
+        stdout: &mut impl Write,
+        position: Duration,
+        speed: f64,
+    ) -> anyhow::Result<()> {
+        self.status_shown = true;
+        let rows = get_terminal_size(self.stdin.as_fd())
+            .context("Failed to get terminal size")?
+            .rows;
+        let status = format!(
+            "\x1b7\x1b[{rows};1H\x1b[0;7m {} {speed}x paused \x1b[0m\x1b[K\x1b8",
+            format_timestamp(position)
+        );
+        stdout
+            .write_all(status.as_bytes())
+            .context("Write to stdout")
+    }
+
+    fn clear_status(&mut self, stdout: &mut impl Write) -> anyhow::Result<()> {
+        self.status_shown = false;
+        let rows = get_terminal_size(self.stdin.as_fd())
+            .context("Failed to get terminal size")?
+            .rows;
+        let clear = format!("\x1b7\x1b[{rows};1H\x1b[0m\x1b[2K\x1b8");
+        stdout
+            .write_all(clear.as_bytes())
+            .context("Write to stdout")
+    }
+}
+
+fn format_timestamp(timestamp: Duration) -> String {
+    let secs = timestamp.as_secs();
+    let millis = timestamp.subsec_millis();
+    if secs >= 3600 {
+        format!(
+            "{}:{:02}:{:02}.{millis:03}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    } else {
+        format!("{:02}:{:02}.{millis:03}", secs / 60, secs % 60)
+    }
+}
+
+/// What to do with the output event waiting to be written, after waiting for its time
+enum Wait {
+    /// Write it, it is due
+    Due,
+    /// Write it now, the playback is stepped while paused
+    Step,
+    /// Write it and everything up to the next marker or barrier
+    NextMarker,
+    /// Drop it and replay the recording up to the previous marker or barrier
+    PreviousMarker,
+    Quit,
+}
+
+struct Player<'a, W: Write> {
+    cmd: &'a PlayCmd,
+    events: RecordingReader,
+    stdout: W,
+    controls: Option<Controls>,
+    speed: f64,
+    /// Event returned again by the next call to `next_event`
+    peeked: Option<(Duration, RecordingEvent)>,
+    /// Number of events read from the recording
+    event_index: usize,
+    /// Timestamp in the recording of the last event
+    position: Duration,
+    /// Index of the last written output event
+    written_index: usize,
+    /// Timestamp in the recording of the last output
+    previous_output: Duration,
+    /// The recording time of the last output, with pauses shortened by --idle-time-limit
+    playback_time: Duration,
+    /// Wall-clock time at which the playback was at the given playback time
+    anchor: (Instant, Duration),
+    /// The playback time when paused
+    paused: Option<Duration>,
+    /// Index and timestamp of the markers and barriers read so far, for seeking backwards
+    markers: Vec<(usize, Duration)>,
+}
+
+impl<'a, W: Write> Player<'a, W> {
+    fn new(cmd: &'a PlayCmd, controls: Option<Controls>, stdout: W) -> anyhow::Result<Self> {
+        Ok(Self {
+            cmd,
+            events: open_recording(&cmd.recording)?,
+            stdout,
+            controls,
+            speed: cmd.speed,
+            peeked: None,
+            event_index: 0,
+            position: Duration::ZERO,
+            written_index: 0,
+            previous_output: Duration::ZERO,
+            playback_time: Duration::ZERO,
+            anchor: (Instant::now(), Duration::ZERO),
+            paused: None,
+            markers: Vec::new(),
+        })
+    }
+
+    fn play(&mut self) -> anyhow::Result<()> {
+        let max_delta = Duration::from_micros(self.cmd.max_accuracy_delta_us);
+
+        while let Some((timestamp, event)) = self.next_event()? {
+            let RecordingEvent::Output(data) = event else {
+                continue;
+            };
+            let due = self.advance_output(timestamp);
+
+            let late = self.clock().saturating_sub(due).div_f64(self.speed);
+            if late > max_delta {
+                bail!("Playback too slow: maximum delta {max_delta:?}, actual delta: {late:?}");
+            }
+
+            match self.wait_until(due)? {
+                Wait::Due => self.write_output(&data)?,
+                Wait::Step => {
+                    self.write_output(&data)?;
+                    self.reanchor();
+                    self.update_status()?;
+                }
+                Wait::NextMarker => {
+                    self.write_output(&data)?;
+                    self.fast_forward(|_, _, event| {
+                        matches!(
+                            event,
+                            RecordingEvent::Marker(_) | RecordingEvent::BarrierUnlocked(_)
+                        )
+                    })?;
+                    self.reanchor();
+                    self.update_status()?;
+                }
+                Wait::PreviousMarker => {
+                    self.seek_back()?;
+                    self.update_status()?;
+                }
+                Wait::Quit => break,
+            }
+        }
+
+        if let Some(controls) = self.controls.as_mut().filter(|c| c.status_shown) {
+            controls.clear_status(&mut self.stdout)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next event, None at the end of the recording or the --stop-at position
+    fn next_event(&mut self) -> anyhow::Result<Option<(Duration, RecordingEvent)>> {
+        if let Some(event) = self.peeked.take() {
+            return Ok(Some(event));
+        }
+        let Some(event) = self.events.next() else {
+            return Ok(None);
+        };
+        let (timestamp, event) = event.context("Failed to load recording")?;
+        if let Some(stop_at) = &self.cmd.stop_at {
+            if stop_at.reached(timestamp, &event) {
+                return Ok(None);
+            }
+        }
+
+        self.event_index += 1;
+        self.position = timestamp;
+        if let RecordingEvent::Marker(_) | RecordingEvent::BarrierUnlocked(_) = event {
+            // When seeking backwards the markers are read again
+            if self
+                .markers
+                .last()
+                .is_none_or(|(i, _)| *i < self.event_index)
+            {
+                self.markers.push((self.event_index, timestamp));
+            }
+        }
+        Ok(Some((timestamp, event)))
+    }
+
+    /// Updates the playback time for an output at `timestamp` and returns it
+    fn advance_output(&mut self, timestamp: Duration) -> Duration {
+        let mut pause = timestamp.saturating_sub(self.previous_output);
+        if let Some(idle_time_limit) = self.cmd.idle_time_limit {
+            pause = pause.min(idle_time_limit);
+        }
+        self.previous_output = timestamp;
+        self.playback_time += pause;
+        self.playback_time
+    }
+
+    /// The current playback time
+    fn clock(&self) -> Duration {
+        match self.paused {
+            Some(paused) => paused,
+            None => self.anchor.1 + self.anchor.0.elapsed().mul_f64(self.speed),
+        }
+    }
+
+    /// Continues the playback from the last output, from now on
+    fn reanchor(&mut self) {
+        self.anchor = (Instant::now(), self.playback_time);
+        if self.paused.is_some() {
+            self.paused = Some(self.playback_time);
+        }
+    }
+
+    fn write_output(&mut self, data: &Data) -> anyhow::Result<()> {
+        self.stdout.write_all(data).context("Write to stdout")?;
+        self.written_index = self.event_index;
+        Ok(())
+    }
+
+    /// Draws the status line while paused, clears it when the playback resumes
+    fn update_status(&mut self) -> anyhow::Result<()> {
+        let Some(controls) = self.controls.as_mut().filter(|c| c.status) else {
+            return Ok(());
+        };
+        if self.paused.is_some() {
+            controls.draw_status(&mut self.stdout, self.position, self.speed)
+        } else if controls.status_shown {
+            controls.clear_status(&mut self.stdout)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Waits until the playback time `due`, handling the keyboard controls in the meantime
+    fn wait_until(&mut self, due: Duration) -> anyhow::Result<Wait> {
+        loop {
+            let clock = self.clock();
+            let timeout = match self.paused {
+                Some(_) => None,
+                None if clock >= due => return Ok(Wait::Due),
+                None => Some((due - clock).div_f64(self.speed)),
+            };
+
+            let Some(controls) = &mut self.controls else {
+                std::thread::sleep(timeout.unwrap_or_default());
+                continue;
+            };
+            let keys = controls.read_keys(timeout)?;
+            for &key in &keys {
+                if let Some(wait) = self.handle_key(key) {
+                    return Ok(wait);
                 }
             }
-            stdout.write_all(&data).context("Write to stdout")?;
-            let elapsed = begin.elapsed()?;
-            last_timestamp += elapsed;
+            if !keys.is_empty() {
+                self.update_status()?;
+            }
+        }
+    }
+
+    /// Handles a control key, returns what to do with the waiting output if it ends the wait
+    fn handle_key(&mut self, key: u8) -> Option<Wait> {
+        match key {
+            b' ' => {
+                self.paused = match self.paused {
+                    Some(paused) => {
+                        self.anchor = (Instant::now(), paused);
+                        None
+                    }
+                    None => Some(self.clock()),
+                };
+            }
+            b'.' => {
+                self.paused = Some(self.clock());
+                return Some(Wait::Step);
+            }
+            b'+' => self.set_speed(self.speed * 2.0),
+            b'-' => self.set_speed(self.speed / 2.0),
+            b']' => return Some(Wait::NextMarker),
+            b'[' => return Some(Wait::PreviousMarker),
+            b'q' | b'\x03' => return Some(Wait::Quit),
+            _ => (),
+        }
+        None
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.anchor = (Instant::now(), self.clock());
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    /// Writes the output immediately until an event for which `until` returns true (given the
+    /// event index, timestamp and the event), which is left to be played next. Returns false if
+    /// the end of the recording was reached instead.
+    fn fast_forward(
+        &mut self,
+        until: impl Fn(usize, Duration, &RecordingEvent) -> bool,
+    ) -> anyhow::Result<bool> {
+        while let Some((timestamp, event)) = self.next_event()? {
+            if until(self.event_index, timestamp, &event) {
+                self.previous_output = timestamp;
+                self.peeked = Some((timestamp, event));
+                return Ok(true);
+            }
+            if let RecordingEvent::Output(data) = event {
+                self.advance_output(timestamp);
+                self.stdout.write_all(&data).context("Write to stdout")?;
+                self.written_index = self.event_index;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Replays the recording from the start up to the previous marker or barrier
+    fn seek_back(&mut self) -> anyhow::Result<()> {
+        // Markers after the last output are skipped, the playback is already at them
+        let target = self
+            .markers
+            .iter()
+            .rev()
+            .find(|(index, _)| *index < self.written_index)
+            .map(|(index, _)| *index);
+
+        self.events = open_recording(&self.cmd.recording)?;
+        self.peeked = None;
+        self.event_index = 0;
+        self.position = Duration::ZERO;
+        self.written_index = 0;
+        self.previous_output = Duration::ZERO;
+        self.playback_time = Duration::ZERO;
+        // Clear the screen and reset the terminal state before replaying
+        self.stdout.write_all(b"\x1bc").context("Write to stdout")?;
+        if let Some(controls) = &mut self.controls {
+            controls.status_shown = false;
         }
+        if let Some(target) = target {
+            self.fast_forward(|index, _, _| index == target)?;
+        }
+        self.reanchor();
         Ok(())
     }
 }
+
+fn open_recording(path: &Path) -> anyhow::Result<RecordingReader> {
+    RecordingReader::open(path).context("Failed to load recording")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd::play::{
+        format_timestamp, parse_position, PlayCmd, PlaybackPosition, Player, Wait,
+    };
+    use crate::file_format::{save_recording_termrec, RecordingEvent, RecordingMetadata};
+    use clap::Parser;
+    use std::ffi::OsStr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// Saves the events as a recording and parses the play command line for it
+    fn play_cmd(name: &str, events: &[(u64, RecordingEvent)], args: &[&str]) -> PlayCmd {
+        let path = std::env::temp_dir().join(format!("termrec-test-{name}-{}", std::process::id()));
+        let events: Vec<_> = events
+            .iter()
+            .map(|(ms, event)| (Duration::from_millis(*ms), event.clone()))
+            .collect();
+        save_recording_termrec(&RecordingMetadata::default(), &events, &path).unwrap();
+        let mut cmdline = vec![OsStr::new("play"), path.as_os_str()];
+        cmdline.extend(args.iter().map(OsStr::new));
+        PlayCmd::parse_from(cmdline)
+    }
+
+    fn output(data: &str) -> RecordingEvent {
+        RecordingEvent::Output(Arc::from(data.as_bytes()))
+    }
+
+    fn marker(name: &str) -> RecordingEvent {
+        RecordingEvent::Marker(Arc::from(name.as_bytes()))
+    }
+
+    #[test]
+    fn test_parse_position() {
+        let timestamp = |s| match parse_position(s).unwrap() {
+            PlaybackPosition::Timestamp(timestamp) => Some(timestamp),
+            PlaybackPosition::Marker(_) => None,
+        };
+        let marker = |s| match parse_position(s).unwrap() {
+            PlaybackPosition::Timestamp(_) => None,
+            PlaybackPosition::Marker(name) => Some(name),
+        };
+        assert_eq!(timestamp("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(timestamp("1m30s"), Some(Duration::from_secs(90)));
+        assert_eq!(marker("intro").as_deref(), Some("intro"));
+        assert_eq!(marker("marker:intro").as_deref(), Some("intro"));
+        assert_eq!(marker("marker:10s").as_deref(), Some("10s"));
+        assert!(parse_position("marker:").is_err());
+    }
+
+    #[test]
+    fn test_idle_time_limit() {
+        let cmd = play_cmd("idle-time-limit", &[], &["--idle-time-limit", "1s"]);
+        let unlimited_cmd = PlayCmd::parse_from([OsStr::new("play"), cmd.recording.as_os_str()]);
+        let mut player = Player::new(&cmd, None, Vec::new()).unwrap();
+        let mut unlimited = Player::new(&unlimited_cmd, None, Vec::new()).unwrap();
+        std::fs::remove_file(&cmd.recording).unwrap();
+
+        let ms = Duration::from_millis;
+        assert_eq!(player.advance_output(ms(1)), ms(1));
+        assert_eq!(player.advance_output(ms(5000)), ms(1001));
+        assert_eq!(player.advance_output(ms(5100)), ms(1101));
+        assert_eq!(unlimited.advance_output(ms(1)), ms(1));
+        assert_eq!(unlimited.advance_output(ms(5000)), ms(5000));
+    }
+
+    #[test]
+    fn test_stop_at() {
+        let events = [(1, output("a")), (2, marker("end")), (3, output("b"))];
+        let played = |stop_at: &str| {
+            let cmd = play_cmd("stop-at", &events, &["--stop-at", stop_at]);
+            let mut player = Player::new(&cmd, None, Vec::new()).unwrap();
+            let result = player.fast_forward(|_, _, _| false);
+            std::fs::remove_file(&cmd.recording).unwrap();
+            assert!(!result.unwrap());
+            player.stdout
+        };
+        assert_eq!(played("marker:end"), b"a");
+        assert_eq!(played("end"), b"a");
+        assert_eq!(played("3ms"), b"a");
+        assert_eq!(played("4ms"), b"ab");
+        assert_eq!(played("marker:missing"), b"ab");
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(Duration::ZERO), "00:00.000");
+        assert_eq!(format_timestamp(Duration::from_millis(61_500)), "01:01.500");
+        assert_eq!(
+            format_timestamp(Duration::from_millis(3_723_004)),
+            "1:02:03.004"
+        );
+    }
+
+    #[test]
+    fn test_wait_until() {
+        let cmd = play_cmd("wait-until", &[(1, output("a"))], &["--speed", "4"]);
+        let mut player = Player::new(&cmd, None, Vec::new()).unwrap();
+        std::fs::remove_file(&cmd.recording).unwrap();
+
+        // 200ms of the recording take 50ms at 4x
+        player.reanchor();
+        let start = Instant::now();
+        assert!(matches!(
+            player.wait_until(Duration::from_millis(200)).unwrap(),
+            Wait::Due
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(matches!(
+            player.wait_until(Duration::ZERO).unwrap(),
+            Wait::Due
+        ));
+
+        assert!(player.handle_key(b' ').is_none());
+        let paused = player.clock();
+        assert_eq!(player.paused, Some(paused));
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(player.clock(), paused);
+        assert!(matches!(player.handle_key(b'.'), Some(Wait::Step)));
+        assert!(player.handle_key(b'+').is_none());
+        assert_eq!(player.speed, 8.0);
+        assert!(player.handle_key(b' ').is_none());
+        assert_eq!(player.paused, None);
+        assert!(player.clock() >= paused);
+        assert!(matches!(player.handle_key(b']'), Some(Wait::NextMarker)));
+        assert!(matches!(
+            player.handle_key(b'['),
+            Some(Wait::PreviousMarker)
+        ));
+        assert!(matches!(player.handle_key(b'q'), Some(Wait::Quit)));
+        assert!(player.handle_key(b'x').is_none());
+    }
+
+    #[test]
+    fn test_seek_back() {
+        let events = [
+            (1, output("a")),
+            (2, marker("one")),
+            (3, output("b")),
+            (4, marker("two")),
+            (5, output("c")),
+        ];
+        let cmd = play_cmd("seek-back", &events, &[]);
+        let mut player = Player::new(&cmd, None, Vec::new()).unwrap();
+
+        assert!(!player.fast_forward(|_, _, _| false).unwrap());
+        assert_eq!(player.stdout, b"abc");
+
+        // Replayed up to the marker before the last output
+        player.seek_back().unwrap();
+        assert_eq!(player.stdout, b"abc\x1bcab");
+        assert_eq!(player.playback_time, Duration::from_millis(3));
+        let next = player.next_event().unwrap();
+        assert_eq!(next, Some((Duration::from_millis(4), marker("two"))));
+        player.peeked = next;
+
+        player.seek_back().unwrap();
+        let result = player.stdout.clone();
+        let next = player.next_event().unwrap();
+        std::fs::remove_file(&cmd.recording).unwrap();
+        assert_eq!(result, b"abc\x1bcab\x1bca");
+        assert_eq!(next, Some((Duration::from_millis(2), marker("one"))));
+    }
+}
@@ -1,28 +1,35 @@
 use crate::cmd::transform::TransformCmd;
 use crate::file_format::{
-    load_input, save_recording_termrec, InputEvent, RecordingEvent, SimulationEvent,
+    is_variable_name, load_input, Barrier, ChildExit, InputEvent, InputVariables, KeysEvent,
+    MouseEvent, RecordingEvent, RecordingMetadata, RecordingWriter, SignalEvent, SimulationEvent,
+    TerminalSize,
 };
-use crate::utils::find_subslice;
+use crate::keys::{encode_keys, encode_paste};
+use crate::screen::Screen;
+use crate::terminal::{get_terminal_size, set_terminal_size, RawMode};
+use crate::unbuffered_stdout::UnbufferedStdout;
+use crate::utils::{find_subslice, fnv1a_64, parse_duration};
 use anyhow::{bail, Context};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::pty::{forkpty, ForkptyResult, Winsize};
 use nix::sys::select::{select, FdSet};
-use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signal::{kill, killpg, SigSet, Signal};
 use nix::sys::signalfd::{SfdFlags, SignalFd};
-use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
-use nix::unistd::{read, Pid};
+use nix::sys::time::{TimeVal, TimeValLike};
+use nix::sys::wait::{waitid, waitpid, Id, WaitPidFlag, WaitStatus};
+use nix::unistd::{gethostname, read, tcgetpgrp, write, Pid};
 use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::io::{stdin, Write};
 use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::mpsc::Receiver;
-use std::sync::{mpsc, Arc};
+use std::process::{Command, ExitCode};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread::JoinHandle;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::{fs, thread};
 
 /// Run a program and record it's terminal IO
@@ -32,6 +39,11 @@ pub struct RecordCmd {
     #[arg(short, long)]
     pub input: Option<PathBuf>,
 
+    /// Record an interactive session, forwarding the keyboard of the current terminal to the
+    /// program and showing its output
+    #[arg(long, conflicts_with = "input")]
+    pub interactive: bool,
+
     #[arg(short, long)]
     pub verbose: bool,
 
@@ -57,70 +69,230 @@ pub struct RecordCmd {
     )]
     pub output_dir: Option<PathBuf>,
 
+    /// Width of the terminal in columns [default: 80, or the current terminal with --interactive]
+    #[arg(long)]
+    pub cols: Option<u16>,
+
+    /// Height of the terminal in rows [default: 24, or the current terminal with --interactive]
+    #[arg(long)]
+    pub rows: Option<u16>,
+
+    /// Width of the terminal in pixels (0 means unknown)
+    #[arg(long)]
+    pub pixel_width: Option<u16>,
+
+    /// Height of the terminal in pixels (0 means unknown)
+    #[arg(long)]
+    pub pixel_height: Option<u16>,
+
+    /// When to flush the recorded events to the output file
+    #[arg(long, value_enum, default_value_t)]
+    pub flush: FlushPolicy,
+
+    /// Fail if a barrier of the input is not unlocked in time (e.g. `30s`), can be overridden
+    /// for each barrier using `w:timeout:<duration>:...`
+    #[arg(long, value_parser = parse_duration)]
+    pub barrier_timeout: Option<Duration>,
+
+    /// Kill the program (SIGKILL) when a barrier times out, instead of waiting for it to exit
+    #[arg(long)]
+    pub kill_on_barrier_timeout: bool,
+
+    /// What to do when the input ends and the program is still running: `wait` until it exits,
+    /// `wait:<duration>` at most the duration and then kill it (SIGKILL), or `terminate[:<grace>]`
+    /// it (SIGTERM) and kill it if it doesn't exit within the grace period [default grace: 5s].
+    /// Also applies when the input fails (e.g. a barrier timed out), `wait` terminates it then.
+    #[arg(long, value_parser = parse_input_end_policy, default_value = "wait")]
+    pub on_input_end: InputEndPolicy,
+
+    /// Kill the program (SIGKILL) if it is still running after the duration (e.g. `5m`)
+    #[arg(long, value_parser = parse_duration)]
+    pub max_duration: Option<Duration>,
+
+    /// Define a `${NAME}` variable of the input (NAME=VALUE), undefined variables are taken from
+    /// the environment
+    #[arg(long, short = 'D', value_parser = parse_define)]
+    pub define: Vec<(String, String)>,
+
     pub command: Vec<String>,
 }
 
+/// Parses a `--define NAME=VALUE` argument
+pub fn parse_define(s: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = s.split_once('=').context("Expected NAME=VALUE")?;
+    if !is_variable_name(name) {
+        bail!("Invalid variable name: {name:?}");
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// What `record` does when the input ends and the program is still running
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InputEndPolicy {
+    /// Wait until the program exits
+    #[default]
+    Wait,
+    /// Wait at most the duration, then kill the program
+    WaitTimeout(Duration),
+    /// Terminate the program, and kill it if it doesn't exit within the grace period
+    Terminate { grace: Duration },
+}
+
+const DEFAULT_TERMINATE_GRACE: Duration = Duration::from_secs(5);
+
+/// Parses `wait`, `wait:<duration>`, `terminate` or `terminate:<grace>`
+pub fn parse_input_end_policy(s: &str) -> anyhow::Result<InputEndPolicy> {
+    let (name, duration) = match s.split_once(':') {
+        Some((name, duration)) => (name, Some(parse_duration(duration)?)),
+        None => (s, None),
+    };
+    Ok(match (name, duration) {
+        ("wait", None) => InputEndPolicy::Wait,
+        ("wait", Some(timeout)) => InputEndPolicy::WaitTimeout(timeout),
+        ("terminate", grace) => InputEndPolicy::Terminate {
+            grace: grace.unwrap_or(DEFAULT_TERMINATE_GRACE),
+        },
+        _ => bail!("Expected wait, wait:<duration>, terminate or terminate:<grace>"),
+    })
+}
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum FlushPolicy {
+    /// After every event
+    Event,
+    /// At most FLUSH_INTERVAL (100ms) after an event was recorded
+    #[default]
+    Periodic,
+    /// Only when the write buffer is full and at the end of the recording
+    Exit,
+}
+
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How much of the output is reported when a barrier times out
+const LAST_OUTPUT_LEN: usize = 256;
+
 impl RecordCmd {
-    pub(crate) fn run(self) -> anyhow::Result<()> {
-        if let Some(output) = self.output {
-            record_cmd(
-                &output,
-                self.child_stderr.as_deref(),
-                self.input.as_deref(),
-                &self.command,
-                self.verbose,
-            )?;
-        } else if let Some(output_dir) = self.output_dir {
+    /// Records the command, returning the exit code of the recorded program
+    pub(crate) fn run(self) -> anyhow::Result<ExitCode> {
+        let child_exit = self.record()?;
+        if child_exit != ChildExit::Exited(0) {
+            log::info!("Recorded program terminated: {child_exit:?}");
+        }
+        Ok(ExitCode::from(child_exit.code() as u8))
+    }
+
+    /// Records the command, returning how the recorded program terminated
+    pub(crate) fn record(self) -> anyhow::Result<ChildExit> {
+        let default_size = if self.interactive {
+            get_terminal_size(stdin().as_fd()).context("Failed to get current terminal size")?
+        } else {
+            TerminalSize::default()
+        };
+        let terminal_size = TerminalSize {
+            cols: self.cols.unwrap_or(default_size.cols),
+            rows: self.rows.unwrap_or(default_size.rows),
+            xpixel: self.pixel_width.unwrap_or(default_size.xpixel),
+            ypixel: self.pixel_height.unwrap_or(default_size.ypixel),
+        };
+
+        let child_exit = if let Some(output) = &self.output {
+            record_cmd(&self, output, terminal_size)?
+        } else if let Some(output_dir) = &self.output_dir {
             // Allow existing empty directory or create a new directory
             let output_is_empty_dir =
-                fs::read_dir(&output_dir).is_ok_and(|mut d| d.next().is_none());
+                fs::read_dir(output_dir).is_ok_and(|mut d| d.next().is_none());
             if !output_is_empty_dir {
-                fs::create_dir(&output_dir).context("Failed to create output directory")?;
+                fs::create_dir(output_dir).context("Failed to create output directory")?;
             }
 
             let recording_path = output_dir.join("recording.termrec");
-            record_cmd(
-                &recording_path,
-                self.child_stderr.as_deref(),
-                self.input.as_deref(),
-                &self.command,
-                self.verbose,
-            )?;
+            let child_exit = record_cmd(&self, &recording_path, terminal_size)?;
             TransformCmd {
                 recording: recording_path,
-                output_dir,
+                output_dir: output_dir.clone(),
+                plain: false,
             }
             .run()?;
+            child_exit
         } else {
             unreachable!();
+        };
+
+        Ok(child_exit)
+    }
+}
+
+/// The recording being written, shared by the main and the input thread
+#[derive(Clone)]
+struct EventLog {
+    start: SystemTime,
+    flush_policy: FlushPolicy,
+    writer: Arc<Mutex<RecordingWriter>>,
+}
+
+impl EventLog {
+    fn new(start: SystemTime, flush_policy: FlushPolicy, writer: RecordingWriter) -> Self {
+        Self {
+            start,
+            flush_policy,
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+
+    fn record(&self, event: RecordingEvent) -> anyhow::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // Take the timestamp while holding the lock, so the events are written in order
+        let timestamp = self.start.elapsed().unwrap();
+        writer.write_event(timestamp, &event)?;
+        match self.flush_policy {
+            FlushPolicy::Event => writer.flush(),
+            FlushPolicy::Periodic if writer.since_last_flush() >= FLUSH_INTERVAL => writer.flush(),
+            FlushPolicy::Periodic | FlushPolicy::Exit => Ok(()),
         }
+    }
 
+    /// Flushes the events recorded since the last flush, when using FlushPolicy::Periodic
+    fn flush_if_due(&self) -> anyhow::Result<()> {
+        if self.flush_policy != FlushPolicy::Periodic {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock().unwrap();
+        if writer.since_last_flush() >= FLUSH_INTERVAL {
+            writer.flush()?;
+        }
         Ok(())
     }
+
+    fn finish(self) -> anyhow::Result<()> {
+        let writer = Arc::into_inner(self.writer).context("Recording is still in use")?;
+        writer.into_inner().unwrap().finish()
+    }
 }
 
 struct Recorder {
-    start: SystemTime,
+    log: EventLog,
     read_buffer: Box<[u8]>,
-    events: Vec<(Duration, RecordingEvent)>,
     data_tx: Option<mpsc::Sender<Msg>>,
+    mirror: Option<UnbufferedStdout>,
 }
 
 impl Recorder {
     const READ_BUFFER_SIZE: usize = 2048 * 2048; // Same as mosh maximum terminal size
 
-    fn begin(time_start: SystemTime, data_tx: Option<mpsc::Sender<Msg>>) -> Self {
+    fn begin(log: EventLog, data_tx: Option<mpsc::Sender<Msg>>, mirror_output: bool) -> Self {
         Self {
-            start: time_start,
+            log,
             read_buffer: Box::new([0; Self::READ_BUFFER_SIZE]),
-            events: Vec::new(),
             data_tx,
+            mirror: mirror_output.then(UnbufferedStdout::lock),
         }
     }
 
-    fn record(&mut self, data: Arc<[u8]>) {
-        let timestamp = self.start.elapsed().unwrap();
+    fn record(&mut self, data: Arc<[u8]>) -> anyhow::Result<()> {
         log::trace!("Out: {data:?}, {:?}", String::from_utf8_lossy(&data[..]));
+        // Record the output before the input thread can react to it
+        self.log.record(RecordingEvent::Output(data.clone()))?;
 
         if let Some(data_tx) = &self.data_tx {
             // The input thread could quit early, ignore the error, and don't attempt to send again
@@ -128,24 +300,31 @@ impl Recorder {
                 self.data_tx = None;
             }
         }
-        self.events.push((timestamp, RecordingEvent::Output(data)));
+        if let Some(mirror) = &mut self.mirror {
+            mirror.write_all(&data).context("Write to stdout")?;
+        }
+        Ok(())
     }
 
     fn record_from_fd(&mut self, fd: BorrowedFd) -> anyhow::Result<()> {
         loop {
             match read(fd.as_raw_fd(), &mut self.read_buffer) {
                 Ok(0) | Err(Errno::EAGAIN) | Err(Errno::EIO) => break Ok(()),
-                Ok(n) => self.record(Arc::from(&self.read_buffer[..n])),
+                Ok(n) => self.record(Arc::from(&self.read_buffer[..n]))?,
                 Err(e) => Err(e).context("read from term")?,
             }
         }
     }
 
-    fn finish(self) -> Vec<(Duration, RecordingEvent)> {
+    fn record_event(&mut self, event: RecordingEvent) -> anyhow::Result<()> {
+        self.log.record(event)
+    }
+
+    fn finish(self) -> EventLog {
         if let Some(tx) = self.data_tx {
             let _ = tx.send(Msg::End);
         }
-        self.events
+        self.log
     }
 }
 
@@ -156,42 +335,99 @@ fn make_nonblocking(fd: RawFd) -> nix::Result<()> {
     Ok(())
 }
 
-fn record_term(term: OwnedFd, child: Pid, recorder: &mut Recorder) -> anyhow::Result<()> {
+fn write_all_nonblocking(fd: BorrowedFd, mut data: &[u8]) -> nix::Result<()> {
+    while !data.is_empty() {
+        match write(fd, data) {
+            Ok(n) => data = &data[n..],
+            Err(Errno::EAGAIN) => thread::yield_now(),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn record_term(
+    term: OwnedFd,
+    child: Pid,
+    recorder: &mut Recorder,
+    signals: SignalFd,
+    interactive: bool,
+    max_duration: Option<Duration>,
+) -> anyhow::Result<ChildExit> {
     make_nonblocking(term.as_raw_fd()).context("Make term fd nonblocking")?;
 
     let term_fd = term.as_fd();
+    let stdin = stdin();
+    let stdin_fd = stdin.as_fd();
+    let mut stdin_open = interactive;
+    let mut input_buffer = [0u8; 4096];
+    let _raw_mode = interactive.then(RawMode::enable).transpose()?;
 
     let mut rfds = FdSet::new();
-    let mut sigmask = SigSet::empty();
-    sigmask.add(Signal::SIGCHLD);
-    sigmask.thread_block().unwrap();
-    let sigchild =
-        SignalFd::with_flags(&sigmask, SfdFlags::SFD_NONBLOCK).context("Create SignalFd")?;
-    let sigchild_fd = sigchild.as_fd();
+    let signals_fd = signals.as_fd();
+    // Cleared once the program is killed
+    let mut max_deadline = max_duration.map(|max_duration| Instant::now() + max_duration);
 
     loop {
         rfds.insert(term_fd);
-        rfds.insert(sigchild_fd.as_fd());
-        select(
-            Some(sigchild_fd.as_raw_fd() + 1),
-            &mut rfds,
-            None,
-            None,
-            None,
-        )
-        .unwrap();
+        rfds.insert(signals_fd);
+        if stdin_open {
+            rfds.insert(stdin_fd);
+        }
+        let wait = match max_deadline {
+            Some(deadline) => {
+                FLUSH_INTERVAL.min(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => FLUSH_INTERVAL,
+        };
+        let mut timeout = TimeVal::microseconds(wait.as_micros() as i64);
+        select(None, &mut rfds, None, None, Some(&mut timeout)).unwrap();
+        recorder.log.flush_if_due()?;
+
+        if max_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            log::warn!("The program is still running after --max-duration, killing it");
+            stop_program(&recorder.log, child, "max-duration", Signal::SIGKILL)?;
+            max_deadline = None;
+        }
 
         if rfds.contains(term_fd) {
             recorder.record_from_fd(term_fd)?;
         }
 
-        if let Ok(Some(_)) = sigchild.read_signal() {
+        if stdin_open && rfds.contains(stdin_fd) {
+            match read(stdin_fd.as_raw_fd(), &mut input_buffer) {
+                Ok(0) => stdin_open = false,
+                Ok(n) => {
+                    let data: Arc<[u8]> = Arc::from(&input_buffer[..n]);
+                    write_all_nonblocking(term_fd, &data).context("Write input to term")?;
+                    log::trace!("Wrote input: {data:?}");
+                    recorder.record_event(RecordingEvent::InputRealized(data))?;
+                }
+                Err(Errno::EAGAIN) | Err(Errno::EINTR) => (),
+                Err(e) => Err(e).context("read from stdin")?,
+            }
+        }
+
+        while let Ok(Some(siginfo)) = signals.read_signal() {
+            if siginfo.ssi_signo == Signal::SIGWINCH as u32 {
+                let size =
+                    get_terminal_size(stdin_fd).context("Failed to get current terminal size")?;
+                resize_terminal(term_fd, child, size)?;
+                recorder.record_event(RecordingEvent::Resize(size))?;
+                continue;
+            }
+
             match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
                 Ok(WaitStatus::StillAlive) => (),
-                Ok(status) => {
-                    log::trace!("Child process exited: {status:?}");
-                    return Ok(());
+                Ok(WaitStatus::Exited(_, code)) => {
+                    log::trace!("Child process exited: {code}");
+                    return Ok(ChildExit::Exited(code));
                 }
+                Ok(WaitStatus::Signaled(_, signal, _)) => {
+                    log::trace!("Child process killed by signal: {signal}");
+                    return Ok(ChildExit::Signaled(signal as i32));
+                }
+                Ok(status) => log::trace!("Child process status changed: {status:?}"),
                 Err(err) => bail!("WaitPid failed {err}"),
             }
         }
@@ -203,125 +439,472 @@ enum Msg {
     End,
 }
 
-fn block_until_found_needle(
-    control_rx: &Receiver<Msg>,
-    collected_data: &mut Vec<u8>,
-    needle: &[u8],
-    verbose: bool,
-) -> bool {
-    loop {
-        match control_rx.recv().expect("Recording thread panicked") {
-            Msg::Data(data) => {
-                collected_data.extend(&data[..]);
-                if let Some(index) = find_subslice(collected_data, needle) {
-                    let drain = collected_data.drain(..index + needle.len());
-                    if verbose {
-                        let drained: Vec<u8> = drain.collect();
-                        log::trace!("{needle:?}({l}bytes) IN {collected_data:?}({l2}bytes), draining {drained:?}", l = needle.len(), l2 = collected_data.len() );
-                    }
+/// The output of the program as seen by the input thread, used to check the barriers
+struct BarrierState {
+    /// Output not yet consumed by an output or regex barrier
+    collected_data: Vec<u8>,
+    /// The end of the output, to report when a barrier times out
+    last_output: Vec<u8>,
+    screen: Screen,
+    /// The program quit, no more output will be received
+    ended: bool,
+}
 
-                    return true;
+impl BarrierState {
+    fn new(terminal_size: TerminalSize) -> Self {
+        Self {
+            collected_data: Vec::with_capacity(Recorder::READ_BUFFER_SIZE),
+            last_output: Vec::with_capacity(LAST_OUTPUT_LEN * 2),
+            screen: Screen::new(terminal_size),
+            ended: false,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.collected_data.extend(data);
+        self.screen.process(data);
+        self.last_output.extend(data);
+        if self.last_output.len() > LAST_OUTPUT_LEN {
+            self.last_output
+                .drain(..self.last_output.len() - LAST_OUTPUT_LEN);
+        }
+    }
+
+    /// Processes the output received so far, without blocking
+    fn receive_pending(&mut self, control_rx: &Receiver<Msg>) {
+        while !self.ended {
+            match control_rx.try_recv() {
+                Ok(Msg::Data(data)) => self.push(&data),
+                Ok(Msg::End) => self.ended = true,
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Waits until the program quits, ignoring the rest of the output
+    fn wait_for_end(&mut self, control_rx: &Receiver<Msg>) {
+        while !self.ended {
+            match control_rx.recv() {
+                Ok(Msg::Data(_)) => (),
+                Ok(Msg::End) | Err(_) => self.ended = true,
+            }
+        }
+    }
+
+    /// Checks whether the barrier is unlocked, the output up to the end of the match is consumed
+    /// by the output and regex barriers. The screen barriers consume all the output so far, so
+    /// the later barriers don't match output from before them.
+    fn unlocked(&mut self, barrier: &Barrier, verbose: bool) -> bool {
+        let match_end = match barrier {
+            Barrier::Output(needle) => {
+                find_subslice(&self.collected_data, needle).map(|index| index + needle.len())
+            }
+            Barrier::Regex(regex) => regex.find(&self.collected_data).map(|m| m.end()),
+            Barrier::Screen(text) => {
+                if !self.screen.render(false).contains(text.as_str()) {
+                    return false;
                 }
+                Some(self.collected_data.len())
+            }
+            Barrier::Region {
+                top,
+                left,
+                bottom,
+                right,
+                text,
+            } => {
+                let region = self.screen.region_text(
+                    *top as usize,
+                    *left as usize,
+                    *bottom as usize,
+                    *right as usize,
+                );
+                if !region.contains(text.as_str()) {
+                    return false;
+                }
+                Some(self.collected_data.len())
+            }
+        };
+
+        match match_end {
+            Some(end) => {
+                let drain = self.collected_data.drain(..end);
                 if verbose {
+                    let drained: Vec<u8> = drain.collect();
                     log::trace!(
-                        "{:?} NOT IN {:?}",
-                        String::from_utf8_lossy(needle),
-                        String::from_utf8_lossy(collected_data),
+                        "{barrier:?} IN {:?}({l2}bytes), draining {drained:?}",
+                        self.collected_data,
+                        l2 = self.collected_data.len()
                     );
                 }
+                true
             }
-            Msg::End => {
-                log::warn!("Quit before barrier unlocked");
-                return false;
+            None => {
+                if verbose {
+                    log::trace!(
+                        "{barrier:?} NOT IN {:?}",
+                        String::from_utf8_lossy(&self.collected_data),
+                    );
+                }
+                false
             }
         }
     }
 }
 
+enum BarrierWait {
+    Unlocked,
+    TimedOut,
+    /// The program quit
+    Ended,
+}
+
+fn block_until_unlocked(
+    control_rx: &Receiver<Msg>,
+    state: &mut BarrierState,
+    barrier: &Barrier,
+    timeout: Option<Duration>,
+    verbose: bool,
+) -> BarrierWait {
+    // The output could have already been received while waiting for a previous barrier
+    if state.unlocked(barrier, verbose) {
+        return BarrierWait::Unlocked;
+    }
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    while !state.ended {
+        let msg = match deadline {
+            Some(deadline) => {
+                match control_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) => return BarrierWait::TimedOut,
+                    Err(RecvTimeoutError::Disconnected) => panic!("Recording thread panicked"),
+                }
+            }
+            None => control_rx.recv().expect("Recording thread panicked"),
+        };
+        match msg {
+            Msg::Data(data) => {
+                state.push(&data);
+                if state.unlocked(barrier, verbose) {
+                    return BarrierWait::Unlocked;
+                }
+            }
+            Msg::End => state.ended = true,
+        }
+    }
+    log::warn!("Quit before barrier unlocked");
+    BarrierWait::Ended
+}
+
+fn resize_terminal(term_fd: BorrowedFd, child: Pid, size: TerminalSize) -> anyhow::Result<()> {
+    set_terminal_size(term_fd, size).context("Failed to resize terminal")?;
+    // The kernel only notifies the foreground process group, make sure the child itself gets
+    // notified too.
+    if let Err(e) = kill(child, Signal::SIGWINCH) {
+        log::warn!("Failed to send SIGWINCH to child: {e}");
+    }
+    log::debug!("Resized terminal: {size:?}");
+    Ok(())
+}
+
+/// Settings of the input thread, from the `record` arguments
+struct InputSettings {
+    terminal_size: TerminalSize,
+    barrier_timeout: Option<Duration>,
+    kill_on_barrier_timeout: bool,
+    on_input_end: InputEndPolicy,
+    verbose: bool,
+}
+
 fn spawn_input_thread(
-    time_start: SystemTime,
+    log: EventLog,
     term_fd: OwnedFd,
+    child: Pid,
     input_events: Vec<SimulationEvent>,
     control_rx: Receiver<Msg>,
-    verbose: bool,
-) -> JoinHandle<Vec<(Duration, RecordingEvent)>> {
+    settings: InputSettings,
+) -> JoinHandle<anyhow::Result<()>> {
     thread::spawn(move || {
-        let mut recorded_events = Vec::with_capacity(input_events.len());
-        let mut collected_data: Vec<u8> = Vec::with_capacity(Recorder::READ_BUFFER_SIZE);
+        let mut barrier_state = BarrierState::new(settings.terminal_size);
         let mut out = File::from(term_fd);
-        let mut last_timestamp = Duration::from_secs(0);
-
-        for event in input_events {
-            match event {
-                SimulationEvent::Input(InputEvent { timestamp, data }) => {
-                    let begin = SystemTime::now();
-                    if timestamp >= last_timestamp {
-                        let delta = timestamp - last_timestamp;
-                        thread::sleep(delta);
-                    } else {
-                        log::warn!(
-                            "WARNING: Input thread is behind: {:?}",
-                            last_timestamp - timestamp
-                        )
-                    }
-                    out.write_all(&data).unwrap();
-                    log::trace!("Wrote input: {data:?}");
-                    recorded_events.push((
-                        time_start.elapsed().unwrap(),
-                        RecordingEvent::InputRealized(data),
-                    ));
-                    last_timestamp += begin.elapsed().unwrap();
+        let result = send_input(
+            &log,
+            &mut out,
+            child,
+            input_events,
+            &control_rx,
+            &mut barrier_state,
+            &settings,
+        );
+
+        // The policy applies also when the input failed, the program could be waiting for the
+        // rest of it. With the default `wait` it's terminated, instead of waiting forever.
+        barrier_state.receive_pending(&control_rx);
+        if !barrier_state.ended {
+            let (policy, reason) = match (&result, settings.on_input_end) {
+                (Ok(()), policy) => (policy, "input-end"),
+                (Err(_), InputEndPolicy::Wait) => (
+                    InputEndPolicy::Terminate {
+                        grace: DEFAULT_TERMINATE_GRACE,
+                    },
+                    "input-failed",
+                ),
+                (Err(_), policy) => (policy, "input-failed"),
+            };
+            if let Err(e) = stop_after_input(&log, child, &control_rx, policy, reason) {
+                match result {
+                    Ok(()) => return Err(e),
+                    Err(_) => log::warn!("Failed to stop the program: {e:#}"),
                 }
-                SimulationEvent::WaitBarrier(needle) => {
-                    log::debug!("Wait: {needle:?}");
+            }
+        }
+        result
+    })
+}
 
-                    if !block_until_found_needle(&control_rx, &mut collected_data, &needle, verbose)
-                    {
-                        return recorded_events;
+/// Sends the input to the program, waiting for the barriers
+fn send_input(
+    log: &EventLog,
+    out: &mut File,
+    child: Pid,
+    input_events: Vec<SimulationEvent>,
+    control_rx: &Receiver<Msg>,
+    barrier_state: &mut BarrierState,
+    settings: &InputSettings,
+) -> anyhow::Result<()> {
+    let mut last_timestamp = Duration::from_secs(0);
+    for event in input_events {
+        match event {
+            SimulationEvent::Input(InputEvent { timestamp, data }) => {
+                let begin = SystemTime::now();
+                sleep_until_input(timestamp, last_timestamp);
+                out.write_all(&data).unwrap();
+                log::trace!("Wrote input: {data:?}");
+                log.record(RecordingEvent::InputRealized(data))?;
+                last_timestamp += begin.elapsed().unwrap();
+            }
+            SimulationEvent::Keys(KeysEvent { timestamp, keys }) => {
+                let begin = SystemTime::now();
+                sleep_until_input(timestamp, last_timestamp);
+                // The keys are encoded using the modes the program has set until now
+                barrier_state.receive_pending(control_rx);
+                let data = encode_keys(&keys, barrier_state.screen.application_cursor());
+                out.write_all(&data).unwrap();
+                log::trace!("Wrote keys {keys:?}: {data:?}");
+                log.record(RecordingEvent::InputRealized(Arc::from(data)))?;
+                last_timestamp += begin.elapsed().unwrap();
+            }
+            SimulationEvent::Paste(InputEvent { timestamp, data }) => {
+                let begin = SystemTime::now();
+                sleep_until_input(timestamp, last_timestamp);
+                barrier_state.receive_pending(control_rx);
+                let data = encode_paste(&data, barrier_state.screen.bracketed_paste());
+                out.write_all(&data).unwrap();
+                log::trace!("Wrote paste: {data:?}");
+                log.record(RecordingEvent::InputRealized(Arc::from(data)))?;
+                last_timestamp += begin.elapsed().unwrap();
+            }
+            SimulationEvent::Mouse(MouseEvent { timestamp, mouse }) => {
+                let begin = SystemTime::now();
+                sleep_until_input(timestamp, last_timestamp);
+                barrier_state.receive_pending(control_rx);
+                let screen = &barrier_state.screen;
+                if !screen.mouse_tracking() {
+                    log::warn!("Sending {mouse:?}, but the program didn't enable mouse tracking");
+                }
+                let mut data = Vec::new();
+                match mouse.encode(screen.sgr_mouse(), &mut data) {
+                    Ok(()) => {
+                        out.write_all(&data).unwrap();
+                        log::trace!("Wrote mouse {mouse:?}: {data:?}");
+                        log.record(RecordingEvent::InputRealized(Arc::from(data)))?;
                     }
-                    recorded_events.push((
-                        time_start.elapsed().unwrap(),
-                        RecordingEvent::BarrierUnlocked(needle),
-                    ));
-                    last_timestamp = Duration::from_secs(0);
+                    Err(e) => log::warn!("Failed to send {mouse:?}: {e}"),
                 }
-                SimulationEvent::Sleep(duration) => {
-                    thread::sleep(duration);
-                    recorded_events.push((
-                        time_start.elapsed().unwrap(),
-                        RecordingEvent::SleepFinished(duration),
-                    ));
-                    last_timestamp = Duration::from_secs(0);
+                last_timestamp += begin.elapsed().unwrap();
+            }
+            SimulationEvent::Signal(event) => {
+                let begin = SystemTime::now();
+                sleep_until_input(event.timestamp, last_timestamp);
+                match send_signal(out.as_fd(), child, &event) {
+                    Ok(()) => log.record(RecordingEvent::SignalSent(event.description()))?,
+                    Err(e) => log::warn!("Failed to send {}: {e}", event.signal),
                 }
-                SimulationEvent::Marker(data) => recorded_events
-                    .push((time_start.elapsed().unwrap(), RecordingEvent::Marker(data))),
+                last_timestamp += begin.elapsed().unwrap();
+            }
+            SimulationEvent::WaitBarrier { barrier, timeout } => {
+                log::debug!("Wait: {barrier:?}");
+
+                let timeout = timeout.or(settings.barrier_timeout);
+                match block_until_unlocked(
+                    control_rx,
+                    barrier_state,
+                    &barrier,
+                    timeout,
+                    settings.verbose,
+                ) {
+                    BarrierWait::Unlocked => (),
+                    BarrierWait::TimedOut => {
+                        let description = barrier.description();
+                        log.record(RecordingEvent::BarrierTimedOut(description.clone()))?;
+                        // Otherwise the program is stopped after the failed input according
+                        // to --on-input-end
+                        if settings.kill_on_barrier_timeout {
+                            stop_program(log, child, "barrier-timeout", Signal::SIGKILL)?;
+                            barrier_state.wait_for_end(control_rx);
+                        }
+                        bail!(
+                            "Barrier {:?} timed out after {:?}, last output: {:?}",
+                            String::from_utf8_lossy(description.data()),
+                            timeout.unwrap_or_default(),
+                            String::from_utf8_lossy(&barrier_state.last_output),
+                        );
+                    }
+                    BarrierWait::Ended => return Ok(()),
+                }
+                log.record(RecordingEvent::BarrierUnlocked(barrier.description()))?;
+                last_timestamp = Duration::from_secs(0);
+            }
+            SimulationEvent::Sleep(duration) => {
+                thread::sleep(duration);
+                log.record(RecordingEvent::SleepFinished(duration))?;
+                last_timestamp = Duration::from_secs(0);
+            }
+            SimulationEvent::Marker(data) => log.record(RecordingEvent::Marker(data))?,
+            SimulationEvent::Resize(size) => {
+                // The output written before the resize is rendered using the old size
+                barrier_state.receive_pending(control_rx);
+                barrier_state.screen.resize(size);
+                resize_terminal(out.as_fd(), child, size)?;
+                log.record(RecordingEvent::Resize(size))?;
             }
         }
-        recorded_events
+    }
+
+    Ok(())
+}
+
+/// Waits for the program to exit after the input stopped, stopping it according to the policy.
+/// `reason` is why the input stopped, recorded if the program is stopped.
+fn stop_after_input(
+    log: &EventLog,
+    child: Pid,
+    control_rx: &Receiver<Msg>,
+    policy: InputEndPolicy,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let timeout = match policy {
+        InputEndPolicy::Wait => return Ok(()),
+        InputEndPolicy::WaitTimeout(timeout) => timeout,
+        InputEndPolicy::Terminate { grace } => {
+            stop_program(log, child, reason, Signal::SIGTERM)?;
+            grace
+        }
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match control_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Msg::Data(_)) => (),
+            Ok(Msg::End) | Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {
+                log::warn!("The program didn't exit in {timeout:?} ({reason}), killing it");
+                return stop_program(log, child, reason, Signal::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Sends a signal to stop the program and records it, `reason` is why it is stopped
+fn stop_program(log: &EventLog, child: Pid, reason: &str, signal: Signal) -> anyhow::Result<()> {
+    // A program that exited but isn't reaped yet would still "receive" the signal
+    let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT;
+    match waitid(Id::Pid(child), flags) {
+        Ok(WaitStatus::StillAlive) => (),
+        Ok(_) | Err(Errno::ECHILD) => return Ok(()),
+        Err(e) => return Err(e).context("Failed to check whether the program exited"),
+    }
+    match kill(child, signal) {
+        Ok(()) => {
+            let description = format!("{reason}:{}", signal.as_str());
+            log.record(RecordingEvent::Stopped(Arc::from(description.as_bytes())))
+        }
+        // Already exited
+        Err(Errno::ESRCH) => Ok(()),
+        Err(e) => Err(e).context(format!("Failed to send {signal} to the program")),
+    }
+}
+
+/// Sleeps until the input is due, both timestamps are relative to the last barrier or sleep
+fn sleep_until_input(timestamp: Duration, last_timestamp: Duration) {
+    if timestamp >= last_timestamp {
+        thread::sleep(timestamp - last_timestamp);
+    } else {
+        log::warn!(
+            "WARNING: Input thread is behind: {:?}",
+            last_timestamp - timestamp
+        )
+    }
+}
+
+/// Sends the signal to the program, or to the foreground process group of its terminal
+fn send_signal(term_fd: BorrowedFd, child: Pid, event: &SignalEvent) -> nix::Result<()> {
+    if event.process_group {
+        killpg(tcgetpgrp(term_fd)?, event.signal)
+    } else {
+        kill(child, event.signal)
+    }
+}
+
+/// Environment variables saved in the recording, the ones affecting how programs render
+const RECORDED_ENV_VARS: &[&str] = &["TERM", "COLORTERM", "LANG", "LC_ALL", "LC_CTYPE", "SHELL"];
+
+fn recording_metadata(
+    time_start: SystemTime,
+    terminal_size: TerminalSize,
+    command: &[String],
+    input: Option<&Path>,
+) -> anyhow::Result<RecordingMetadata> {
+    let input_hash = input
+        .map(|input| fs::read(input).context("Failed to read input"))
+        .transpose()?
+        .map(|input| format!("fnv1a64:{:016x}", fnv1a_64(&input)));
+
+    Ok(RecordingMetadata {
+        termrec_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        timestamp: time_start
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()
+            .map(|t| t.as_secs()),
+        hostname: gethostname()
+            .ok()
+            .map(|hostname| hostname.to_string_lossy().into_owned()),
+        command: command.to_vec(),
+        terminal_size: Some(terminal_size),
+        env: RECORDED_ENV_VARS
+            .iter()
+            .filter_map(|&name| Some((name.to_string(), std::env::var(name).ok()?)))
+            .collect(),
+        input_hash,
     })
 }
 
 fn record_cmd(
+    cmd: &RecordCmd,
     output: &Path,
-    child_stderr: Option<&Path>,
-    input: Option<&Path>,
-    command: &[String],
-    verbose: bool,
-) -> anyhow::Result<()> {
-    let terminal_size = Winsize {
-        ws_row: 24,
-        ws_col: 80,
-        ws_xpixel: 0,
-        ws_ypixel: 0,
-    };
+    terminal_size: TerminalSize,
+) -> anyhow::Result<ChildExit> {
+    let input = cmd.input.as_deref();
+    let interactive = cmd.interactive;
+    let command = &cmd.command;
 
     let input_events = if let Some(input) = input {
-        load_input(input).context("Failed to load input")?
+        load_input(input, &InputVariables::new(&cmd.define)).context("Failed to load input")?
     } else {
         Vec::new()
     };
 
-    let child_stderr = if let Some(child_stderr) = child_stderr {
+    let child_stderr = if let Some(child_stderr) = &cmd.child_stderr {
         Some(
             OpenOptions::new()
                 .write(true)
@@ -332,43 +915,73 @@ fn record_cmd(
         None
     };
 
-    let f = unsafe { forkpty(Some(&terminal_size), None) }.expect("Failed to fork pty");
+    let f =
+        unsafe { forkpty(Some(&Winsize::from(terminal_size)), None) }.expect("Failed to fork pty");
     match f {
         ForkptyResult::Parent { child, master } => {
             drop(child_stderr);
             let time_start = SystemTime::now();
 
-            let (tx, input_thread) = if !input_events.is_empty() {
+            // Block the signals before spawning the input thread, so the thread inherits the mask
+            // and the signals can only be received through the SignalFd
+            let mut sigmask = SigSet::empty();
+            sigmask.add(Signal::SIGCHLD);
+            if interactive {
+                sigmask.add(Signal::SIGWINCH);
+            }
+            sigmask.thread_block().unwrap();
+            let signals = SignalFd::with_flags(&sigmask, SfdFlags::SFD_NONBLOCK)
+                .context("Create SignalFd")?;
+
+            let metadata = recording_metadata(time_start, terminal_size, command, input)?;
+            let mut writer = RecordingWriter::create(output, &metadata)?;
+            writer.write_event(Duration::ZERO, &RecordingEvent::Resize(terminal_size))?;
+            let log = EventLog::new(time_start, cmd.flush, writer);
+
+            // With an input, the thread is started even when it's empty, to handle --on-input-end
+            let (tx, input_thread) = if input.is_some() {
                 let (tx, rx) = mpsc::channel();
                 let input_thread = spawn_input_thread(
-                    time_start,
+                    log.clone(),
                     master.try_clone().unwrap(),
+                    child,
                     input_events,
                     rx,
-                    verbose,
+                    InputSettings {
+                        terminal_size,
+                        barrier_timeout: cmd.barrier_timeout,
+                        kill_on_barrier_timeout: cmd.kill_on_barrier_timeout,
+                        on_input_end: cmd.on_input_end,
+                        verbose: cmd.verbose,
+                    },
                 );
                 (Some(tx), Some(input_thread))
             } else {
                 (None, None)
             };
 
-            let mut recorder = Recorder::begin(time_start, tx);
-            record_term(master, child, &mut recorder)?;
+            let mut recorder = Recorder::begin(log, tx, interactive);
+            let child_exit = record_term(
+                master,
+                child,
+                &mut recorder,
+                signals,
+                interactive,
+                cmd.max_duration,
+            )?;
+            recorder.record_event(RecordingEvent::Exit(child_exit))?;
 
-            let mut events = recorder.finish();
-            if let Some(input_thread) = input_thread {
-                match input_thread.join() {
-                    Ok(input_thread_events) => {
-                        events.extend(input_thread_events);
-                        events.sort_by_key(|(timestamp, _event)| *timestamp)
-                    }
-                    Err(_) => {
-                        bail!("FAILED to finish: input thread panicked");
-                    }
-                }
-            }
+            let log = recorder.finish();
+            let input_result = match input_thread.map(JoinHandle::join) {
+                Some(Ok(result)) => result,
+                Some(Err(_)) => bail!("FAILED to finish: input thread panicked"),
+                None => Ok(()),
+            };
 
-            save_recording_termrec(events, output).context("Save recording")?;
+            // Save the recording even if the input failed, to see what happened
+            log.finish().context("Save recording")?;
+            input_result.context("Input thread failed")?;
+            Ok(child_exit)
         }
         ForkptyResult::Child => {
             let mut cmd = Command::new(&command[0]);
@@ -381,5 +994,117 @@ fn record_cmd(
             bail!("Failed to exec: {err}");
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd::record::{
+        block_until_unlocked, parse_input_end_policy, stop_after_input, stop_program, BarrierState,
+        BarrierWait, EventLog, FlushPolicy, InputEndPolicy, Msg,
+    };
+    use crate::file_format::{
+        load_recording, Barrier, RecordingEvent, RecordingMetadata, RecordingWriter, TerminalSize,
+    };
+    use nix::sys::signal::Signal;
+    use nix::sys::wait::{waitid, Id, WaitPidFlag};
+    use nix::unistd::Pid;
+    use std::process::Command;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[test]
+    fn test_barrier_timeout() {
+        let size = TerminalSize {
+            cols: 20,
+            rows: 2,
+            xpixel: 0,
+            ypixel: 0,
+        };
+        let mut state = BarrierState::new(size);
+        let (tx, rx) = mpsc::channel();
+        let barrier = Barrier::Output(Arc::from(&b"ok"[..]));
+        let timeout = Some(Duration::from_millis(20));
+
+        tx.send(Msg::Data(Arc::from(&b"not o"[..]))).unwrap();
+        let start = Instant::now();
+        let wait = block_until_unlocked(&rx, &mut state, &barrier, timeout, false);
+        assert!(matches!(wait, BarrierWait::TimedOut));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // The output received before the timeout still counts
+        tx.send(Msg::Data(Arc::from(&b"k"[..]))).unwrap();
+        let wait = block_until_unlocked(&rx, &mut state, &barrier, timeout, false);
+        assert!(matches!(wait, BarrierWait::Unlocked));
+
+        tx.send(Msg::End).unwrap();
+        let wait = block_until_unlocked(&rx, &mut state, &barrier, None, false);
+        assert!(matches!(wait, BarrierWait::Ended));
+    }
+
+    #[test]
+    fn test_parse_input_end_policy() {
+        let parse = |s| parse_input_end_policy(s).unwrap();
+        assert_eq!(parse("wait"), InputEndPolicy::Wait);
+        assert_eq!(
+            parse("wait:5s"),
+            InputEndPolicy::WaitTimeout(Duration::from_secs(5))
+        );
+        assert_eq!(
+            parse("terminate"),
+            InputEndPolicy::Terminate {
+                grace: Duration::from_secs(5)
+            }
+        );
+        assert_eq!(
+            parse("terminate:1s"),
+            InputEndPolicy::Terminate {
+                grace: Duration::from_secs(1)
+            }
+        );
+
+        for invalid in ["", "kill", "wait:", "wait:soon", "terminate:1x", "Wait"] {
+            assert!(parse_input_end_policy(invalid).is_err(), "{invalid}");
+        }
+    }
+    #[test]
+    fn test_stop_after_input() {
+        let path = std::env::temp_dir().join(format!("termrec-test-{}.rec", std::process::id()));
+        let writer = RecordingWriter::create(&path, &RecordingMetadata::default()).unwrap();
+        let log = EventLog::new(SystemTime::now(), FlushPolicy::Event, writer);
+
+        // Exited but not reaped yet, it's not stopped
+        let mut exited = Command::new("true").spawn().unwrap();
+        let pid = Pid::from_raw(exited.id() as i32);
+        waitid(Id::Pid(pid), WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT).unwrap();
+        stop_program(&log, pid, "input-end", Signal::SIGTERM).unwrap();
+        exited.wait().unwrap();
+
+        let mut running = Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = Pid::from_raw(running.id() as i32);
+        let (tx, rx) = mpsc::channel();
+        let waiter = thread::spawn(move || {
+            running.wait().unwrap();
+            tx.send(Msg::End).unwrap();
+        });
+        let policy = InputEndPolicy::Terminate {
+            grace: Duration::from_secs(5),
+        };
+        let start = Instant::now();
+        stop_after_input(&log, pid, &rx, policy, "input-end").unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        waiter.join().unwrap();
+
+        let events = load_recording(&path);
+        std::fs::remove_file(&path).unwrap();
+        let stopped: Vec<_> = events
+            .unwrap()
+            .into_iter()
+            .filter_map(|(_, event)| match event {
+                RecordingEvent::Stopped(data) => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(stopped, [Arc::from(&b"input-end:SIGTERM"[..])]);
+    }
 }
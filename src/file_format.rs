@@ -1,14 +1,27 @@
 #![allow(clippy::write_with_newline)]
 
+use crate::keys::{parse_keys, Key};
+use crate::mouse::Mouse;
+use crate::utils::{find_subslice, parse_duration, XorShift};
 use anyhow::{anyhow, bail, ensure, Context};
+use nix::sys::signal::Signal;
+use regex::bytes::Regex;
+use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+mod script;
+mod ttyrec;
+
+pub use script::{load_recording_script, save_recording_script};
+pub use ttyrec::{load_recording_ttyrec, save_recording_ttyrec};
 
 pub type Data = Arc<[u8]>;
 
@@ -16,16 +29,93 @@ pub type Data = Arc<[u8]>;
 pub enum RecordingEvent {
     Output(Data),
     InputRealized(Data),
-    BarrierUnlocked(Data),
+    BarrierUnlocked(BarrierDescription),
+    /// The barrier was not unlocked in time
+    BarrierTimedOut(BarrierDescription),
+    /// A signal was sent to the program by the input, the data is the
+    /// [description](SignalEvent::description) of the signal
+    SignalSent(Data),
+    /// `record` sent a signal to stop the program, instead of waiting for it to exit, the data
+    /// is `<reason>:<signal>` (e.g. `input-end:SIGTERM`, `max-duration:SIGKILL`)
+    Stopped(Data),
     SleepFinished(Duration),
     Marker(Data),
+    Resize(TerminalSize),
+    Exit(ChildExit),
+}
+
+/// How the recorded program terminated
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChildExit {
+    /// The program exited normally with an exit code
+    Exited(i32),
+    /// The program was terminated by a signal
+    Signaled(i32),
+}
+
+impl ChildExit {
+    /// Exit code following the shell convention (128 + signal number for signals)
+    pub fn code(&self) -> i32 {
+        match *self {
+            ChildExit::Exited(code) => code,
+            ChildExit::Signaled(signal) => 128 + signal,
+        }
+    }
+}
+
+/// Size of the terminal in character cells (and optionally pixels, 0 if unknown)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TerminalSize {
+    pub cols: u16,
+    pub rows: u16,
+    pub xpixel: u16,
+    pub ypixel: u16,
+}
+
+impl Default for TerminalSize {
+    fn default() -> Self {
+        TerminalSize {
+            cols: 80,
+            rows: 24,
+            xpixel: 0,
+            ypixel: 0,
+        }
+    }
+}
+
+/// Information about a recording, that isn't part of the recorded events
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RecordingMetadata {
+    /// Version of termrec that made the recording
+    pub termrec_version: Option<String>,
+    /// When the recording was started (unix timestamp in seconds)
+    pub timestamp: Option<u64>,
+    pub hostname: Option<String>,
+    /// The recorded command and its arguments
+    pub command: Vec<String>,
+    /// Size of the terminal the recording was started with
+    pub terminal_size: Option<TerminalSize>,
+    /// Environment variables of the recording (e.g. TERM, SHELL)
+    pub env: Vec<(String, String)>,
+    /// Hash of the input simulation file the recording was made with
+    pub input_hash: Option<String>,
 }
 
 pub enum SimulationEvent {
     Input(InputEvent),
-    WaitBarrier(Data),
+    Keys(KeysEvent),
+    /// Text pasted into the terminal
+    Paste(InputEvent),
+    Mouse(MouseEvent),
+    Signal(SignalEvent),
+    WaitBarrier {
+        barrier: Barrier,
+        /// Overrides the `--barrier-timeout` of `record`
+        timeout: Option<Duration>,
+    },
     Sleep(Duration),
     Marker(Data),
+    Resize(TerminalSize),
 }
 
 pub struct InputEvent {
@@ -33,8 +123,171 @@ pub struct InputEvent {
     pub data: Data,
 }
 
-const TERMREC_RECORDING_HEADER: &[u8] = b"termrec:v1:rec:";
-const TERMREC_INPUT_HEADER: &[u8] = b"termrec:v1:inp:";
+/// Keys given by name, they are translated to bytes when sent, because some keys depend on the
+/// modes of the terminal
+pub struct KeysEvent {
+    pub timestamp: Duration,
+    pub keys: Vec<Key>,
+}
+
+pub struct MouseEvent {
+    pub timestamp: Duration,
+    pub mouse: Mouse,
+}
+
+/// A signal sent to the program, or to the foreground process group of the terminal (e.g. a
+/// program started by a shell)
+pub struct SignalEvent {
+    pub timestamp: Duration,
+    pub signal: Signal,
+    pub process_group: bool,
+}
+
+impl SignalEvent {
+    /// The signal as saved in the `SignalSent` event of the recording: its name, prefixed by
+    /// `group:` when sent to the process group (e.g. `SIGINT`, `group:SIGTSTP`)
+    pub fn description(&self) -> Data {
+        let group = if self.process_group { "group:" } else { "" };
+        Arc::from(format!("{group}{}", self.signal.as_str()).as_bytes())
+    }
+
+    /// Parses the [description](SignalEvent::description) of a signal
+    pub fn from_description(timestamp: Duration, description: &Data) -> anyhow::Result<Self> {
+        let description = std::str::from_utf8(description).context("Expected UTF-8")?;
+        let (name, process_group) = match description.strip_prefix("group:") {
+            Some(name) => (name, true),
+            None => (description, false),
+        };
+        Ok(SignalEvent {
+            timestamp,
+            signal: parse_signal(name)?,
+            process_group,
+        })
+    }
+}
+
+/// Parses a signal name (`SIGINT` or `INT`) or number
+pub fn parse_signal(name: &str) -> anyhow::Result<Signal> {
+    if let Ok(number) = name.parse::<i32>() {
+        return Signal::try_from(number)
+            .with_context(|| format!("Invalid signal number: {number}"));
+    }
+    let name = name.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{name}")
+    };
+    Signal::from_str(&name).with_context(|| format!("Unknown signal: {name}"))
+}
+
+/// How fast the `t:` input command types, the default for the rest of the file is set by `d:`
+#[derive(Copy, Clone, Debug, Default)]
+struct TypingSpeed {
+    /// Delay between two typed characters
+    delay: Option<Duration>,
+    /// Each delay is randomly made longer or shorter by up to this much
+    jitter: Duration,
+}
+
+/// What a `w:` command of the input waits for before continuing with the input
+#[derive(Clone, Debug)]
+pub enum Barrier {
+    /// The bytes appear in the output of the program
+    Output(Data),
+    /// The regex matches the output of the program
+    Regex(Regex),
+    /// The rendered screen contains the text
+    Screen(String),
+    /// A rectangular region of the rendered screen contains the text, the rows and columns are
+    /// counted from 0 and inclusive
+    Region {
+        top: u16,
+        left: u16,
+        bottom: u16,
+        right: u16,
+        text: String,
+    },
+}
+
+/// A barrier as saved in the `BarrierUnlocked` and `BarrierTimedOut` events of the recording.
+/// The kinds are saved as different events, so any output can be told apart from the others.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BarrierDescription {
+    /// The bytes of an output barrier
+    Output(Data),
+    /// The other barriers in the same format as in the input file without the length (e.g.
+    /// `re:<regex>`, `screen:<text>`)
+    Other(Data),
+}
+
+impl BarrierDescription {
+    pub fn data(&self) -> &Data {
+        match self {
+            BarrierDescription::Output(data) | BarrierDescription::Other(data) => data,
+        }
+    }
+}
+
+impl Barrier {
+    pub fn description(&self) -> BarrierDescription {
+        let description = match self {
+            Barrier::Output(needle) => return BarrierDescription::Output(needle.clone()),
+            Barrier::Regex(regex) => format!("re:{regex}"),
+            Barrier::Screen(text) => format!("screen:{text}"),
+            Barrier::Region {
+                top,
+                left,
+                bottom,
+                right,
+                text,
+            } => format!("region:{top}:{left}:{bottom}:{right}:{text}"),
+        };
+        BarrierDescription::Other(Arc::from(description.as_bytes()))
+    }
+
+    /// Parses the [description](Barrier::description) of a barrier
+    pub fn from_description(description: &BarrierDescription) -> anyhow::Result<Barrier> {
+        let description = match description {
+            BarrierDescription::Output(needle) => return Ok(Barrier::Output(needle.clone())),
+            BarrierDescription::Other(description) => description,
+        };
+        let parse = || -> Option<Barrier> {
+            let description = std::str::from_utf8(description).ok()?;
+            let (kind, rest) = description.split_once(':')?;
+            match kind {
+                "re" => Regex::new(rest).ok().map(Barrier::Regex),
+                "screen" => Some(Barrier::Screen(rest.to_string())),
+                "region" => {
+                    let mut fields = rest.splitn(5, ':');
+                    let mut num = || fields.next()?.parse().ok();
+                    let (top, left, bottom, right) = (num()?, num()?, num()?, num()?);
+                    Some(Barrier::Region {
+                        top,
+                        left,
+                        bottom,
+                        right,
+                        text: fields.next()?.to_string(),
+                    })
+                }
+                _ => None,
+            }
+        };
+        parse().with_context(|| {
+            format!(
+                "Invalid barrier: {:?}",
+                String::from_utf8_lossy(description)
+            )
+        })
+    }
+}
+
+const TERMREC_RECORDING_HEADER: &[u8] = b"termrec:v2:rec:";
+/// Recordings without the metadata block
+const TERMREC_RECORDING_HEADER_V1: &[u8] = b"termrec:v1:rec:";
+/// The `${NAME}` variables are only expanded in the v2 input files
+const TERMREC_INPUT_HEADER: &[u8] = b"termrec:v2:inp:";
+const TERMREC_INPUT_HEADER_V1: &[u8] = b"termrec:v1:inp:";
 
 pub fn parse_event_cmdline(arg: &OsStr) -> anyhow::Result<RecordingEvent> {
     let arg = arg.as_bytes();
@@ -44,7 +297,12 @@ pub fn parse_event_cmdline(arg: &OsStr) -> anyhow::Result<RecordingEvent> {
     let data = Arc::from(data);
     let event = match event {
         b"o:" => RecordingEvent::Output(data),
-        b"w:" => RecordingEvent::BarrierUnlocked(data),
+        b"w:" => RecordingEvent::BarrierUnlocked(BarrierDescription::Output(data)),
+        b"W:" => RecordingEvent::BarrierUnlocked(BarrierDescription::Other(data)),
+        b"t:" => RecordingEvent::BarrierTimedOut(BarrierDescription::Output(data)),
+        b"T:" => RecordingEvent::BarrierTimedOut(BarrierDescription::Other(data)),
+        b"g:" => RecordingEvent::SignalSent(data),
+        b"e:" => RecordingEvent::Stopped(data),
         b"i:" => RecordingEvent::InputRealized(data),
         b"m:" => RecordingEvent::Marker(data),
         _ => bail!("Unknown/unsupported event: {event:?}"),
@@ -55,30 +313,130 @@ pub fn parse_event_cmdline(arg: &OsStr) -> anyhow::Result<RecordingEvent> {
 
 /// Attempts to load a termrec or asciinema recording by autodetecting the format
 pub fn load_recording(recording_file: &Path) -> anyhow::Result<Vec<(Duration, RecordingEvent)>> {
-    let mut file = BufReader::new(File::open(recording_file).unwrap());
-
-    let mut header_buf = [0u8; TERMREC_RECORDING_HEADER.len()];
-    file.read_exact(&mut header_buf).expect("File too small");
-    if header_buf == TERMREC_RECORDING_HEADER {
-        load_recording_termec_format(file).context("Failed to load recording in termrec format")
-    } else if header_buf == TERMREC_INPUT_HEADER {
-        bail!("Invalid file: File is a termrec file, but not a recording. It is an input simulation file!");
-    } else {
-        file.seek(SeekFrom::Start(0))
-            .context("Failed to seek input file, this is required to load asciinema format")?;
-        load_recording_asciinema_format(file)
-            .context("Failed to load recording in asciinema format")
+    load_recording_with_metadata(recording_file).map(|(_metadata, events)| events)
+}
+
+/// Like [load_recording], but also returns the information about the recording
+pub fn load_recording_with_metadata(
+    recording_file: &Path,
+) -> anyhow::Result<(RecordingMetadata, Vec<(Duration, RecordingEvent)>)> {
+    let mut reader = RecordingReader::open(recording_file)?;
+    let events = reader.by_ref().collect::<anyhow::Result<_>>()?;
+    Ok((reader.metadata, events))
+}
+
+/// Reads a termrec or asciinema recording (autodetecting the format) event by event, without
+/// loading the whole recording into memory
+pub struct RecordingReader<R = BufReader<File>> {
+    metadata: RecordingMetadata,
+    /// Event read ahead when opening the recording
+    pending: Option<(Duration, RecordingEvent)>,
+    /// None after the end of the recording or an error
+    source: Option<RecordingSource<R>>,
+}
+
+enum RecordingSource<R> {
+    Termrec(TermrecEvents<R>),
+    Asciicast(AsciicastEvents<R>),
+}
+
+impl RecordingReader {
+    pub fn open(recording_file: &Path) -> anyhow::Result<Self> {
+        let file = File::open(recording_file).context("Failed to open recording")?;
+        Self::new(BufReader::new(file))
     }
 }
 
-pub fn filter_output_events(input: Vec<(Duration, RecordingEvent)>) -> Vec<(Duration, Data)> {
-    input
-        .into_iter()
-        .filter_map(|(timestamp, event)| match event {
-            RecordingEvent::Output(data) => Some((timestamp, data)),
-            _ => None,
+impl<R: BufRead> RecordingReader<R> {
+    fn new(mut file: R) -> anyhow::Result<Self> {
+        let start = file.fill_buf().context("Failed to read recording")?;
+        if start.starts_with(TERMREC_RECORDING_HEADER)
+            || start.starts_with(TERMREC_RECORDING_HEADER_V1)
+        {
+            file.consume(TERMREC_RECORDING_HEADER.len());
+            let mut reader = Self {
+                metadata: RecordingMetadata::default(),
+                pending: None,
+                source: Some(RecordingSource::Termrec(TermrecEvents {
+                    file,
+                    line_num: 0,
+                })),
+            };
+            // The metadata is at the start of the file, read up to the first event to have it
+            reader.pending = reader
+                .next()
+                .transpose()
+                .context("Failed to load recording in termrec format")?;
+            Ok(reader)
+        } else if start.starts_with(TERMREC_INPUT_HEADER)
+            || start.starts_with(TERMREC_INPUT_HEADER_V1)
+        {
+            bail!("Invalid file: File is a termrec file, but not a recording. It is an input simulation file!");
+        } else {
+            let (events, metadata, size) = AsciicastEvents::open(file)
+                .context("Failed to load recording in asciinema format")?;
+            Ok(Self {
+                metadata,
+                pending: Some((Duration::ZERO, RecordingEvent::Resize(size))),
+                source: Some(RecordingSource::Asciicast(events)),
+            })
+        }
+    }
+
+    /// Metadata entries which are not at the start of the recording are available only after
+    /// reading the events preceding them
+    pub fn metadata(&self) -> &RecordingMetadata {
+        &self.metadata
+    }
+
+    pub fn output_events(self) -> impl Iterator<Item = anyhow::Result<(Duration, Data)>> {
+        self.filter_map(|event| match event {
+            Ok((timestamp, RecordingEvent::Output(data))) => Some(Ok((timestamp, data))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
         })
-        .collect()
+    }
+}
+
+impl<R: BufRead> Iterator for RecordingReader<R> {
+    type Item = anyhow::Result<(Duration, RecordingEvent)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(event) = self.pending.take() {
+            return Some(Ok(event));
+        }
+        let result = match self.source.as_mut()? {
+            RecordingSource::Termrec(events) => events.next_event(&mut self.metadata),
+            RecordingSource::Asciicast(events) => events.next_event(),
+        };
+        match result {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => {
+                self.source = None;
+                None
+            }
+            Err(e) => {
+                self.source = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Returns the terminal size the recording was started with, if it was recorded
+pub fn initial_terminal_size(events: &[(Duration, RecordingEvent)]) -> Option<TerminalSize> {
+    match &events[initial_terminal_size_index(events)?].1 {
+        RecordingEvent::Resize(size) => Some(*size),
+        _ => unreachable!(),
+    }
+}
+
+/// Index of the event with the size the recording was started with
+fn initial_terminal_size_index(events: &[(Duration, RecordingEvent)]) -> Option<usize> {
+    events
+        .iter()
+        .take_while(|(_, event)| !matches!(event, RecordingEvent::Output(_)))
+        .position(|(_, event)| matches!(event, RecordingEvent::Resize(_)))
 }
 
 fn validitate_simulation_events(events: &[SimulationEvent]) -> anyhow::Result<()> {
@@ -89,158 +447,1001 @@ fn validitate_simulation_events(events: &[SimulationEvent]) -> anyhow::Result<()
                 ensure!(*timestamp >= last_timestamp, "Invalid timestamp for event: {data:?}. Expected {timestamp:?} >= {last_timestamp:?}");
                 last_timestamp = *timestamp;
             }
-            SimulationEvent::WaitBarrier(_) => {
+            SimulationEvent::Paste(InputEvent { timestamp, data }) => {
+                ensure!(*timestamp >= last_timestamp, "Invalid timestamp for paste: {data:?}. Expected {timestamp:?} >= {last_timestamp:?}");
+                last_timestamp = *timestamp;
+            }
+            SimulationEvent::Mouse(MouseEvent { timestamp, mouse }) => {
+                ensure!(*timestamp >= last_timestamp, "Invalid timestamp for mouse event: {mouse:?}. Expected {timestamp:?} >= {last_timestamp:?}");
+                last_timestamp = *timestamp;
+            }
+            SimulationEvent::Keys(KeysEvent { timestamp, keys }) => {
+                let names: Vec<String> = keys.iter().map(Key::to_string).collect();
+                ensure!(
+                    *timestamp >= last_timestamp,
+                    "Invalid timestamp for keys: {}. Expected {timestamp:?} >= {last_timestamp:?}",
+                    names.join(" ")
+                );
+                last_timestamp = *timestamp;
+            }
+            SimulationEvent::Signal(event @ SignalEvent { timestamp, .. }) => {
+                ensure!(*timestamp >= last_timestamp, "Invalid timestamp for signal: {}. Expected {timestamp:?} >= {last_timestamp:?}", String::from_utf8_lossy(&event.description()));
+                last_timestamp = *timestamp;
+            }
+            SimulationEvent::WaitBarrier { .. } => {
                 last_timestamp = Duration::from_secs(0);
             }
             SimulationEvent::Sleep(_) => {
                 last_timestamp = Duration::from_secs(0);
             }
-            SimulationEvent::Marker(_) => (),
+            SimulationEvent::Marker(_) | SimulationEvent::Resize(_) => (),
         }
     }
     Ok(())
 }
 
-pub fn load_input(file: &Path) -> anyhow::Result<Vec<SimulationEvent>> {
-    let mut file = BufReader::new(File::open(file).context("Failed to open input file")?);
-    let mut header_buf = [0u8; TERMREC_INPUT_HEADER.len()];
-    file.read_exact(&mut header_buf)
-        .context("Invalid file: unknown format")?;
+pub fn load_input(file: &Path, variables: &InputVariables) -> anyhow::Result<Vec<SimulationEvent>> {
+    let mut parser = InputParser {
+        variables,
+        variables_enabled: true,
+        typing_speed: TypingSpeed::default(),
+        // Seeded with a constant, so the same file always produces the same input
+        jitter_rng: XorShift::new(0x5eed),
+        files: Vec::new(),
+    };
+    let mut events = Vec::new();
+    parser.load_file(file, &mut events)?;
 
-    if header_buf == TERMREC_RECORDING_HEADER {
-        bail!("Invalid file: File is a termrec file, but not an input file. It is a recording!");
-    } else if header_buf != TERMREC_INPUT_HEADER {
-        bail!("Invalid file: unknown format");
-    }
+    validitate_simulation_events(&events)?;
+    Ok(events)
+}
 
-    let mut events = Vec::new();
-    let mut line_num = 0;
-    loop {
-        let mut cmd = [0u8; 2];
+/// Values of the `${NAME}` variables in input files: the ones defined using `record --define`,
+/// otherwise the environment variables. The variables are only expanded in the
+/// `termrec:v2:inp:` files, the older files are sent as they are.
+#[derive(Clone, Debug, Default)]
+pub struct InputVariables {
+    defines: HashMap<String, String>,
+    /// Whether the environment variables are used, the default has no variables at all
+    from_env: bool,
+}
 
-        match file.read_exact(&mut cmd) {
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
-            Err(e) => bail!("File read error: {e}"),
-            Ok(()) => (),
+impl InputVariables {
+    pub fn new(defines: &[(String, String)]) -> Self {
+        InputVariables {
+            defines: defines.iter().cloned().collect(),
+            from_env: true,
         }
+    }
 
-        let err_context = || format!("On line {line_num}");
-        let event = match &cmd {
-            b"i:" => {
-                let timestamp_us = read_num(&mut file).with_context(err_context)?;
-                let data = read_data(&mut file).with_context(err_context)?;
+    fn get(&self, name: &str) -> Option<String> {
+        match self.defines.get(name) {
+            Some(value) => Some(value.clone()),
+            None if self.from_env => std::env::var(name).ok(),
+            None => None,
+        }
+    }
 
-                SimulationEvent::Input(InputEvent {
-                    timestamp: Duration::from_micros(timestamp_us),
-                    data,
-                })
-            }
-            b"w:" => {
-                let data = read_data(&mut file).with_context(err_context)?;
-                SimulationEvent::WaitBarrier(data)
-            }
-            b"s:" => {
-                let duration = read_duration(&mut file).with_context(err_context)?;
-                SimulationEvent::Sleep(duration)
-            }
-            b"m:" => {
-                let data = read_data(&mut file).with_context(err_context)?;
-                SimulationEvent::Marker(data)
-            }
-            b"--" => {
-                read_line_comment(&mut file);
+    /// Replaces every `${NAME}` with the value of the variable, `$${` is an escaped `${`
+    pub fn expand(&self, data: &[u8]) -> anyhow::Result<Data> {
+        let mut expanded = Vec::with_capacity(data.len());
+        let mut rest = data;
+        while let Some(start) = find_subslice(rest, b"${") {
+            if start > 0 && rest[start - 1] == b'$' {
+                expanded.extend(&rest[..start - 1]);
+                expanded.extend(b"${");
+                rest = &rest[start + 2..];
                 continue;
             }
-            // Ignore newlines (to make it easier to write the file by hand)
-            b"\\\n" => {
-                line_num += 1;
-                continue;
+            expanded.extend(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after
+                .iter()
+                .position(|&b| b == b'}')
+                .context("Unterminated variable, expected `}`")?;
+            let name = std::str::from_utf8(&after[..end])
+                .ok()
+                .filter(|name| is_variable_name(name))
+                .with_context(|| {
+                    format!(
+                        "Invalid variable name: {:?}",
+                        String::from_utf8_lossy(&after[..end])
+                    )
+                })?;
+            let value = self.get(name).with_context(|| {
+                format!("Undefined variable {name}, define it using --define or the environment")
+            })?;
+            expanded.extend(value.as_bytes());
+            rest = &after[end + 1..];
+        }
+        expanded.extend(rest);
+        Ok(Arc::from(expanded))
+    }
+}
+
+/// Expands the variables in the data, kept as it is without variables (in the v1 input files)
+fn expand_variables(variables: Option<&InputVariables>, data: &[u8]) -> anyhow::Result<Data> {
+    match variables {
+        Some(variables) => variables.expand(data),
+        None => Ok(Arc::from(data)),
+    }
+}
+
+/// Escapes every `${` as `$${`, so `InputVariables::expand` restores the data
+pub fn escape_variables(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while let Some(start) = find_subslice(rest, b"${") {
+        escaped.extend(&rest[..start]);
+        escaped.extend(b"$${");
+        rest = &rest[start + 2..];
+    }
+    escaped.extend(rest);
+    escaped
+}
+
+/// Variable names are made of ASCII letters, digits and `_`, and don't start with a digit
+pub fn is_variable_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Loads input files, expanding the included files, variables and `repeat` blocks
+struct InputParser<'a> {
+    variables: &'a InputVariables,
+    /// Whether the variables are expanded in the file being loaded, only in the v2 files
+    variables_enabled: bool,
+    typing_speed: TypingSpeed,
+    jitter_rng: XorShift,
+    /// The files being loaded, the including files first
+    files: Vec<PathBuf>,
+}
+
+impl InputParser<'_> {
+    fn load_file(&mut self, path: &Path, events: &mut Vec<SimulationEvent>) -> anyhow::Result<()> {
+        let contents = fs::read(path).context("Failed to open input file")?;
+        let header = contents
+            .get(..TERMREC_INPUT_HEADER.len())
+            .context("Invalid file: unknown format")?;
+
+        if header == TERMREC_RECORDING_HEADER || header == TERMREC_RECORDING_HEADER_V1 {
+            bail!(
+                "Invalid file: File is a termrec file, but not an input file. It is a recording!"
+            );
+        }
+        let variables_enabled = match header {
+            TERMREC_INPUT_HEADER => true,
+            TERMREC_INPUT_HEADER_V1 => false,
+            _ => bail!("Invalid file: unknown format"),
+        };
+
+        let path = path.canonicalize().context("Failed to open input file")?;
+        ensure!(
+            !self.files.contains(&path),
+            "The file includes itself: {path:?}"
+        );
+        self.files.push(path);
+        // The typing speed set by `d:` only applies to the rest of the file
+        let typing_speed = self.typing_speed;
+        let including_enabled = std::mem::replace(&mut self.variables_enabled, variables_enabled);
+
+        let mut reader = &contents[TERMREC_INPUT_HEADER.len()..];
+        let mut line_num = 0;
+        if self.parse(&mut reader, &mut line_num, events)? {
+            bail!("Unexpected `}}` on line {line_num}, there is no repeat block to end");
+        }
+
+        self.typing_speed = typing_speed;
+        self.variables_enabled = including_enabled;
+        self.files.pop();
+        Ok(())
+    }
+
+    /// Parses the commands until the end of the file or the `}` ending a repeat block, returns
+    /// whether it stopped at a `}`
+    fn parse(
+        &mut self,
+        reader: &mut &[u8],
+        line_num: &mut usize,
+        events: &mut Vec<SimulationEvent>,
+    ) -> anyhow::Result<bool> {
+        loop {
+            let line = *line_num;
+            let err_context = || format!("On line {line}");
+
+            // Commands are a letter followed by ':', longer words are the `include` and
+            // `repeat` lines
+            match reader.first() {
+                Some(b'}') => {
+                    let rest = read_line_text(reader).with_context(err_context)?;
+                    *line_num += 1;
+                    ensure!(rest == "}", "Unexpected text after `}}` (line {line})");
+                    return Ok(true);
+                }
+                Some(b) if b.is_ascii_alphabetic() && reader.get(1) != Some(&b':') => {
+                    let text = read_line_text(reader).with_context(err_context)?;
+                    *line_num += 1;
+                    self.parse_line(&text, reader, line_num, events)
+                        .with_context(err_context)?;
+                    continue;
+                }
+                _ => (),
             }
-            b"\n\n" => {
-                line_num += 2;
-                continue;
+
+            let Some((cmd, rest)) = reader.split_first_chunk::<2>() else {
+                return Ok(false);
+            };
+            *reader = rest;
+
+            let event = match cmd {
+                b"i:" => {
+                    let timestamp = read_input_duration(reader).with_context(err_context)?;
+                    let data = self.read_text(reader).with_context(err_context)?;
+
+                    SimulationEvent::Input(InputEvent { timestamp, data })
+                }
+                b"k:" => {
+                    let timestamp = read_input_duration(reader).with_context(err_context)?;
+                    let keys =
+                        read_keys_line(reader, self.file_variables()).with_context(err_context)?;
+                    *line_num += 1;
+
+                    SimulationEvent::Keys(KeysEvent { timestamp, keys })
+                }
+                b"t:" => {
+                    let speed =
+                        read_typing_speed(reader, self.typing_speed).with_context(err_context)?;
+                    let text = self.read_text(reader).with_context(err_context)?;
+                    let text = std::str::from_utf8(&text)
+                        .context("Expected UTF-8 text")
+                        .with_context(err_context)?;
+                    type_text(events, text, speed, &mut self.jitter_rng)
+                        .with_context(err_context)?;
+                    continue;
+                }
+                b"d:" => {
+                    self.typing_speed =
+                        read_typing_speed(reader, self.typing_speed).with_context(err_context)?;
+                    continue;
+                }
+                b"p:" => {
+                    let timestamp = read_input_duration(reader).with_context(err_context)?;
+                    let data = self.read_paste(reader).with_context(err_context)?;
+                    SimulationEvent::Paste(InputEvent { timestamp, data })
+                }
+                b"c:" => {
+                    let timestamp = read_input_duration(reader).with_context(err_context)?;
+                    let mouse = read_mouse(reader).with_context(err_context)?;
+                    SimulationEvent::Mouse(MouseEvent { timestamp, mouse })
+                }
+                b"g:" => {
+                    let timestamp = read_input_duration(reader).with_context(err_context)?;
+                    let (signal, process_group) = read_signal(reader).with_context(err_context)?;
+                    SimulationEvent::Signal(SignalEvent {
+                        timestamp,
+                        signal,
+                        process_group,
+                    })
+                }
+                b"w:" => {
+                    read_wait_barrier(reader, self.file_variables()).with_context(err_context)?
+                }
+                b"s:" => {
+                    let duration = read_input_duration(reader).with_context(err_context)?;
+                    SimulationEvent::Sleep(duration)
+                }
+                b"m:" => {
+                    let data = self.read_text(reader).with_context(err_context)?;
+                    SimulationEvent::Marker(data)
+                }
+                b"r:" => {
+                    let cols = read_u16(reader).with_context(err_context)?;
+                    let rows = read_u16(reader).with_context(err_context)?;
+                    SimulationEvent::Resize(TerminalSize {
+                        cols,
+                        rows,
+                        xpixel: 0,
+                        ypixel: 0,
+                    })
+                }
+                b"--" => {
+                    read_line_comment(reader);
+                    continue;
+                }
+                // Ignore newlines (to make it easier to write the file by hand)
+                b"\\\n" => {
+                    *line_num += 1;
+                    continue;
+                }
+                b"\n\n" => {
+                    *line_num += 2;
+                    continue;
+                }
+                other => {
+                    bail!(
+                        "Unknown input command {other:?} ({:?}) (line {line})",
+                        String::from_utf8_lossy(other)
+                    )
+                }
+            };
+            events.push(event);
+        }
+    }
+
+    /// Parses the `include <file>` and `repeat <count> {` lines
+    fn parse_line(
+        &mut self,
+        text: &str,
+        reader: &mut &[u8],
+        line_num: &mut usize,
+        events: &mut Vec<SimulationEvent>,
+    ) -> anyhow::Result<()> {
+        let text = expand_variables(self.file_variables(), text.as_bytes())?;
+        let text = std::str::from_utf8(&text).context("Expected UTF-8 text")?;
+        let (keyword, args) = text.split_once(' ').unwrap_or((text, ""));
+        match keyword {
+            "include" => {
+                let file = args.trim();
+                ensure!(!file.is_empty(), "Expected the file to include");
+                // Relative to the including file
+                let dir = self.files.last().and_then(|path| path.parent());
+                let path = dir.map_or_else(|| PathBuf::from(file), |dir| dir.join(file));
+                self.load_file(&path, events)
+                    .with_context(|| format!("Failed to include {file:?}"))
             }
-            other => {
-                bail!(
-                    "Unknown input command {other:?} ({:?}) (line {line_num})",
-                    String::from_utf8_lossy(other)
-                )
+            "repeat" => {
+                let count = args
+                    .trim()
+                    .strip_suffix('{')
+                    .context("Expected `{` after the repeat count")?
+                    .trim();
+                let count: usize = count
+                    .parse()
+                    .with_context(|| format!("Invalid repeat count: {count:?}"))?;
+
+                // The block is parsed again for every repetition, the input continues after the
+                // input of the previous repetition
+                let (block, block_line) = (*reader, *line_num);
+                let first_event = events.len();
+                if !self.parse(reader, line_num, events)? {
+                    bail!("Missing `}}` ending the repeat block");
+                }
+                if count == 0 {
+                    events.truncate(first_event);
+                }
+                let block = &block[..block.len() - reader.len()];
+                for _ in 1..count {
+                    let (mut block, mut line_num) = (block, block_line);
+                    let mut repetition = Vec::new();
+                    self.parse(&mut block, &mut line_num, &mut repetition)?;
+                    shift_segment(&mut repetition, segment_end(events));
+                    events.append(&mut repetition);
+                }
+                Ok(())
             }
-        };
-        events.push(event);
+            _ => bail!("Unknown input command {keyword:?}"),
+        }
     }
 
-    validitate_simulation_events(&events)?;
-    Ok(events)
+    /// The variables expanded in the file being loaded, None if they are not expanded
+    fn file_variables(&self) -> Option<&InputVariables> {
+        self.variables_enabled.then_some(self.variables)
+    }
+
+    /// Reads the data of a command and replaces the variables in it
+    fn read_text(&self, reader: &mut &[u8]) -> anyhow::Result<Data> {
+        expand_variables(self.file_variables(), &read_data(reader)?)
+    }
+
+    /// Reads the text of a `p:` command: `<len>:<text>`, or `file:<len>:<path>` to paste the
+    /// contents of the file (relative to the input file)
+    fn read_paste(&self, reader: &mut &[u8]) -> anyhow::Result<Data> {
+        if reader.first().is_some_and(u8::is_ascii_digit) {
+            return self.read_text(reader);
+        }
+        let kind = read_field(reader)?;
+        ensure!(
+            kind == b"file",
+            "Expected the length of the text or `file`, got {:?}",
+            String::from_utf8_lossy(&kind)
+        );
+        let file = self.read_text(reader)?;
+        let file = Path::new(OsStr::from_bytes(&file));
+        let path = match self.files.last().and_then(|path| path.parent()) {
+            Some(dir) => dir.join(file),
+            None => file.to_path_buf(),
+        };
+        let contents = fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+        Ok(Arc::from(contents))
+    }
 }
 
-pub fn save_recording_termrec(
-    events: Vec<(Duration, RecordingEvent)>,
-    path: &Path,
-) -> anyhow::Result<()> {
+pub fn save_input_termrec(events: &[SimulationEvent], path: &Path) -> anyhow::Result<()> {
     let mut f = File::create(path).context("Failed to open output file")?;
-    f.write_all(TERMREC_RECORDING_HEADER)?;
+    f.write_all(TERMREC_INPUT_HEADER)?;
     f.write_all(b"\\\n")?;
-    for (timestamp, event) in events {
-        let timestamp: u64 = timestamp
-            .as_micros()
-            .try_into()
-            .context("Timestamp too large")?;
-
-        let write_cmd_data = |f: &mut File, cmd, data: Data| {
-            let data_len: u64 = data.len() as u64;
-            write!(f, "{cmd}:{timestamp}:{data_len}:")?;
+    for event in events {
+        // The data of every command is expanded when loaded
+        let write_data = |f: &mut File, data: &Data| {
+            let data = escape_variables(data);
+            write!(f, "{}:", data.len())?;
             f.write_all(&data)?;
             write!(f, "\\\n")?;
             Ok::<_, anyhow::Error>(())
         };
 
-        let write_cmd_duration = |f: &mut File, cmd, duration: Duration| {
-            write!(f, "{cmd}:{timestamp}:{}:\\\n", duration.as_micros() as u64)?;
-            Ok::<_, anyhow::Error>(())
-        };
-
         match event {
-            RecordingEvent::Marker(data) => write_cmd_data(&mut f, 'm', data),
-            RecordingEvent::Output(data) => write_cmd_data(&mut f, 'o', data),
-            RecordingEvent::InputRealized(data) => write_cmd_data(&mut f, 'i', data),
-            RecordingEvent::SleepFinished(duration) => write_cmd_duration(&mut f, 's', duration),
-            RecordingEvent::BarrierUnlocked(data) => write_cmd_data(&mut f, 'w', data),
+            SimulationEvent::Input(InputEvent { timestamp, data }) => {
+                write!(f, "i:{}:", timestamp.as_micros())?;
+                write_data(&mut f, data)
+            }
+            SimulationEvent::Keys(KeysEvent { timestamp, keys }) => {
+                let names: Vec<String> = keys.iter().map(Key::to_string).collect();
+                write!(f, "k:{}:{}\\\n", timestamp.as_micros(), names.join(" ")).map_err(Into::into)
+            }
+            SimulationEvent::Paste(InputEvent { timestamp, data }) => {
+                write!(f, "p:{}:", timestamp.as_micros())?;
+                write_data(&mut f, data)
+            }
+            SimulationEvent::Mouse(MouseEvent { timestamp, mouse }) => write!(
+                f,
+                "c:{}:{}:{}:{}:\\\n",
+                timestamp.as_micros(),
+                mouse.action,
+                mouse.col,
+                mouse.row
+            )
+            .map_err(Into::into),
+            SimulationEvent::Signal(event) => {
+                write!(f, "g:{}:", event.timestamp.as_micros())?;
+                f.write_all(&event.description())?;
+                write!(f, ":\\\n").map_err(Into::into)
+            }
+            SimulationEvent::WaitBarrier { barrier, timeout } => {
+                write!(f, "w:")?;
+                if let Some(timeout) = timeout {
+                    write!(f, "timeout:{}:", timeout.as_micros())?;
+                }
+                match barrier {
+                    Barrier::Output(needle) => write_data(&mut f, needle),
+                    Barrier::Regex(regex) => {
+                        write!(f, "re:")?;
+                        write_data(&mut f, &Arc::from(regex.as_str().as_bytes()))
+                    }
+                    Barrier::Screen(text) => {
+                        write!(f, "screen:")?;
+                        write_data(&mut f, &Arc::from(text.as_bytes()))
+                    }
+                    Barrier::Region {
+                        top,
+                        left,
+                        bottom,
+                        right,
+                        text,
+                    } => {
+                        write!(f, "region:{top}:{left}:{bottom}:{right}:")?;
+                        write_data(&mut f, &Arc::from(text.as_bytes()))
+                    }
+                }
+            }
+            SimulationEvent::Sleep(duration) => {
+                write!(f, "s:{}:\\\n", duration.as_micros()).map_err(Into::into)
+            }
+            SimulationEvent::Marker(data) => {
+                write!(f, "m:")?;
+                write_data(&mut f, data)
+            }
+            SimulationEvent::Resize(size) => {
+                write!(f, "r:{}:{}:\\\n", size.cols, size.rows).map_err(Into::into)
+            }
         }
         .context("Failed to write to output file")?;
     }
     Ok(())
 }
 
-fn read_num(reader: &mut impl BufRead) -> anyhow::Result<u64> {
+pub fn save_recording_termrec(
+    metadata: &RecordingMetadata,
+    events: &[(Duration, RecordingEvent)],
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut writer = RecordingWriter::create(path, metadata)?;
+    for (timestamp, event) in events {
+        writer.write_event(*timestamp, event)?;
+    }
+    writer.finish()
+}
+
+/// Writes a termrec recording event by event, so a recording that gets interrupted is still
+/// usable up to the last flush
+pub struct RecordingWriter {
+    file: BufWriter<File>,
+    last_flush: Instant,
+}
+
+impl RecordingWriter {
+    pub fn create(path: &Path, metadata: &RecordingMetadata) -> anyhow::Result<Self> {
+        let mut file = BufWriter::new(File::create(path).context("Failed to open output file")?);
+        file.write_all(TERMREC_RECORDING_HEADER)
+            .and_then(|()| file.write_all(b"\\\n"))
+            .context("Failed to write to output file")?;
+        write_metadata_termrec(metadata, &mut file).context("Failed to write to output file")?;
+        Ok(Self {
+            file,
+            last_flush: Instant::now(),
+        })
+    }
+
+    pub fn write_event(
+        &mut self,
+        timestamp: Duration,
+        event: &RecordingEvent,
+    ) -> anyhow::Result<()> {
+        write_event_termrec(&mut self.file, timestamp, event)
+            .context("Failed to write to output file")
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.last_flush = Instant::now();
+        self.file.flush().context("Failed to write to output file")
+    }
+
+    pub fn since_last_flush(&self) -> Duration {
+        self.last_flush.elapsed()
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.flush()
+    }
+}
+
+fn write_event_termrec(
+    f: &mut impl Write,
+    timestamp: Duration,
+    event: &RecordingEvent,
+) -> anyhow::Result<()> {
+    let timestamp: u64 = timestamp
+        .as_micros()
+        .try_into()
+        .context("Timestamp too large")?;
+
+    match event {
+        RecordingEvent::Marker(data) => write_cmd_data(f, 'm', timestamp, data),
+        RecordingEvent::Output(data) => write_cmd_data(f, 'o', timestamp, data),
+        RecordingEvent::InputRealized(data) => write_cmd_data(f, 'i', timestamp, data),
+        RecordingEvent::SleepFinished(duration) => {
+            write!(f, "s:{timestamp}:{}:\\\n", duration.as_micros() as u64)
+        }
+        // The output barriers are `w:` and `t:`, the other kinds use the uppercase letters
+        RecordingEvent::BarrierUnlocked(BarrierDescription::Output(data)) => {
+            write_cmd_data(f, 'w', timestamp, data)
+        }
+        RecordingEvent::BarrierUnlocked(BarrierDescription::Other(data)) => {
+            write_cmd_data(f, 'W', timestamp, data)
+        }
+        RecordingEvent::BarrierTimedOut(BarrierDescription::Output(data)) => {
+            write_cmd_data(f, 't', timestamp, data)
+        }
+        RecordingEvent::BarrierTimedOut(BarrierDescription::Other(data)) => {
+            write_cmd_data(f, 'T', timestamp, data)
+        }
+        RecordingEvent::SignalSent(data) => write_cmd_data(f, 'g', timestamp, data),
+        RecordingEvent::Stopped(data) => write_cmd_data(f, 'e', timestamp, data),
+        RecordingEvent::Resize(size) => write!(
+            f,
+            "r:{timestamp}:{}:{}:{}:{}:\\\n",
+            size.cols, size.rows, size.xpixel, size.ypixel
+        ),
+        RecordingEvent::Exit(ChildExit::Exited(code)) => write!(f, "x:{timestamp}:{code}:\\\n"),
+        RecordingEvent::Exit(ChildExit::Signaled(signal)) => {
+            write!(f, "k:{timestamp}:{signal}:\\\n")
+        }
+    }?;
+    Ok(())
+}
+
+fn write_cmd_data(
+    f: &mut impl Write,
+    cmd: char,
+    timestamp: u64,
+    data: &[u8],
+) -> std::io::Result<()> {
+    write!(f, "{cmd}:{timestamp}:{}:", data.len())?;
+    f.write_all(data)?;
+    write!(f, "\\\n")
+}
+
+/// Writes the metadata as `h:<key>:<value len>:<value>` entries, repeated keys (env, command)
+/// form a list
+fn write_metadata_termrec(metadata: &RecordingMetadata, f: &mut impl Write) -> anyhow::Result<()> {
+    let mut entries: Vec<(&str, String)> = Vec::new();
+    if let Some(version) = &metadata.termrec_version {
+        entries.push(("termrec_version", version.clone()));
+    }
+    if let Some(timestamp) = metadata.timestamp {
+        entries.push(("timestamp", timestamp.to_string()));
+    }
+    if let Some(hostname) = &metadata.hostname {
+        entries.push(("hostname", hostname.clone()));
+    }
+    for arg in &metadata.command {
+        entries.push(("command", arg.clone()));
+    }
+    if let Some(size) = metadata.terminal_size {
+        entries.push(("terminal_size", format!("{}x{}", size.cols, size.rows)));
+    }
+    for (name, value) in &metadata.env {
+        entries.push(("env", format!("{name}={value}")));
+    }
+    if let Some(hash) = &metadata.input_hash {
+        entries.push(("input_hash", hash.clone()));
+    }
+
+    for (key, value) in entries {
+        write!(f, "h:{key}:{}:", value.len())?;
+        f.write_all(value.as_bytes())?;
+        write!(f, "\\\n")?;
+    }
+    Ok(())
+}
+
+fn read_metadata_entry(
+    reader: &mut impl BufRead,
+    metadata: &mut RecordingMetadata,
+) -> anyhow::Result<()> {
+    let mut key = Vec::new();
+    reader
+        .read_until(b':', &mut key)
+        .context("Read key until separator")?;
+    if key.pop() != Some(b':') {
+        return Err(unexpected_eof(
+            "Expected ':' separator after the metadata key",
+        ));
+    }
+    let value = read_data(reader)?;
+    let value = String::from_utf8_lossy(&value).into_owned();
+
+    match &key[..] {
+        b"termrec_version" => metadata.termrec_version = Some(value),
+        b"timestamp" => {
+            metadata.timestamp = Some(value.parse().context("Invalid timestamp")?);
+        }
+        b"hostname" => metadata.hostname = Some(value),
+        b"command" => metadata.command.push(value),
+        b"terminal_size" => {
+            metadata.terminal_size =
+                Some(parse_cols_x_rows(&value).context("Invalid terminal size")?);
+        }
+        b"env" => {
+            let (name, value) = value.split_once('=').context("Invalid env entry")?;
+            metadata.env.push((name.to_string(), value.to_string()));
+        }
+        b"input_hash" => metadata.input_hash = Some(value),
+        // Allow adding more keys without breaking older versions
+        key => log::debug!(
+            "Ignoring unknown metadata: {}",
+            String::from_utf8_lossy(key)
+        ),
+    }
+    Ok(())
+}
+
+/// Parses a terminal size written as `<cols>x<rows>`
+fn parse_cols_x_rows(size: &str) -> Option<TerminalSize> {
+    let (cols, rows) = size.split_once('x')?;
+    Some(TerminalSize {
+        cols: cols.parse().ok()?,
+        rows: rows.parse().ok()?,
+        xpixel: 0,
+        ypixel: 0,
+    })
+}
+
+/// Reads a non-empty field terminated by the ':' separator, the separator is not included
+fn read_field(reader: &mut impl BufRead) -> anyhow::Result<Vec<u8>> {
     let mut buf = Vec::new();
     let num_bytes = reader
         .read_until(b':', &mut buf)
-        .context("Read num until separator")?;
-    if num_bytes == 0 {
-        bail!("Unexpected EOF");
-    } else if num_bytes <= 1 || buf[num_bytes - 1] != b':' {
-        bail!(
+        .context("Read field until separator")?;
+    // The separator is only missing at the end of the file
+    if num_bytes == 0 || buf[num_bytes - 1] != b':' {
+        return Err(unexpected_eof(format!(
             "Expected ':' separator, {:?}",
             String::from_utf8_lossy(&buf)
-        );
+        )));
+    } else if num_bytes == 1 {
+        bail!("Expected a field before the ':' separator");
     }
-    let num_str = std::str::from_utf8(&buf[..num_bytes - 1])
-        .context("Expected UTF-8 representing a number")?;
+    buf.pop();
+    Ok(buf)
+}
+
+/// An error for a file ending in the middle of a record, see `is_unexpected_eof`
+fn unexpected_eof(message: impl Into<String>) -> anyhow::Error {
+    std::io::Error::new(ErrorKind::UnexpectedEof, message.into()).into()
+}
+
+/// Whether the error is caused by a short read, the file ending in the middle of a record
+fn is_unexpected_eof(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == ErrorKind::UnexpectedEof)
+    })
+}
+
+fn read_num(reader: &mut impl BufRead) -> anyhow::Result<u64> {
+    let field = read_field(reader)?;
+    let num_str = std::str::from_utf8(&field).context("Expected UTF-8 representing a number")?;
     let num = u64::from_str(num_str).context("Expected a number")?;
     Ok(num)
 }
 
+/// Reads a duration in an input file: microseconds, or a number with a unit (e.g. `150ms`, `2s`,
+/// `1m30s`)
+fn read_input_duration(reader: &mut impl BufRead) -> anyhow::Result<Duration> {
+    let field = read_field(reader)?;
+    let field = std::str::from_utf8(&field).context("Expected UTF-8 duration")?;
+    parse_input_duration(field)
+}
+
+fn parse_input_duration(s: &str) -> anyhow::Result<Duration> {
+    if s.bytes().all(|b| b.is_ascii_digit()) {
+        let us = u64::from_str(s).context("Expected a number")?;
+        return Ok(Duration::from_micros(us));
+    }
+    // A number without a unit is always in microseconds, `1.5` is not accepted as seconds
+    ensure!(
+        s.ends_with(|c: char| c.is_ascii_alphabetic()),
+        "Expected microseconds or a duration with a unit (e.g. 150ms), got {s:?}"
+    );
+    parse_duration(s)
+}
+
+/// Reads the options of the `t:` and `d:` input commands, they change the given speed:
+/// `cps:<characters per second>:`, `delay:<duration>:` (between two characters) and
+/// `jitter:<duration>:`
+fn read_typing_speed(
+    reader: &mut impl BufRead,
+    mut speed: TypingSpeed,
+) -> anyhow::Result<TypingSpeed> {
+    while reader
+        .fill_buf()
+        .context("File read error")?
+        .first()
+        .is_some_and(u8::is_ascii_alphabetic)
+    {
+        let option = read_field(reader)?;
+        match &option[..] {
+            b"cps" => {
+                let field = read_field(reader)?;
+                let cps = std::str::from_utf8(&field)
+                    .ok()
+                    .and_then(|cps| f64::from_str(cps).ok())
+                    .filter(|cps| cps.is_finite() && *cps > 0.0)
+                    .context("Expected a positive number of characters per second")?;
+                let delay = Duration::try_from_secs_f64(1.0 / cps)
+                    .context("Too few characters per second")?;
+                speed.delay = Some(delay);
+            }
+            b"delay" => speed.delay = Some(read_input_duration(reader).context("Invalid delay")?),
+            b"jitter" => speed.jitter = read_input_duration(reader).context("Invalid jitter")?,
+            other => bail!(
+                "Unknown typing option {:?} (expected cps, delay or jitter)",
+                String::from_utf8_lossy(other)
+            ),
+        }
+    }
+    Ok(speed)
+}
+
+/// Expands the text typed by the `t:` input command into an input event for every character.
+/// The first character is typed one delay after the previous input (or barrier/sleep).
+fn type_text(
+    events: &mut Vec<SimulationEvent>,
+    text: &str,
+    speed: TypingSpeed,
+    rng: &mut XorShift,
+) -> anyhow::Result<()> {
+    let delay = speed
+        .delay
+        .context("Unknown typing speed, use `cps:` or `delay:`, or set the default using `d:`")?;
+    let mut timestamp = segment_end(events);
+    for ch in text.chars() {
+        let jitter = speed.jitter.as_secs_f64() * (rng.next_f64() * 2.0 - 1.0);
+        timestamp += Duration::from_secs_f64((delay.as_secs_f64() + jitter).max(0.0));
+        events.push(SimulationEvent::Input(InputEvent {
+            timestamp,
+            data: Arc::from(ch.encode_utf8(&mut [0; 4]).as_bytes()),
+        }));
+    }
+    Ok(())
+}
+
+/// Timestamp of the last input since the last barrier or sleep, the input timestamps are relative
+/// to it
+fn segment_end(events: &[SimulationEvent]) -> Duration {
+    for event in events.iter().rev() {
+        match event {
+            SimulationEvent::Input(InputEvent { timestamp, .. })
+            | SimulationEvent::Keys(KeysEvent { timestamp, .. })
+            | SimulationEvent::Paste(InputEvent { timestamp, .. })
+            | SimulationEvent::Mouse(MouseEvent { timestamp, .. })
+            | SimulationEvent::Signal(SignalEvent { timestamp, .. }) => return *timestamp,
+            SimulationEvent::WaitBarrier { .. } | SimulationEvent::Sleep(_) => break,
+            SimulationEvent::Marker(_) | SimulationEvent::Resize(_) => (),
+        }
+    }
+    Duration::ZERO
+}
+
+/// Moves the input before the first barrier or sleep later by the offset
+fn shift_segment(events: &mut [SimulationEvent], offset: Duration) {
+    for event in events {
+        match event {
+            SimulationEvent::Input(InputEvent { timestamp, .. })
+            | SimulationEvent::Keys(KeysEvent { timestamp, .. })
+            | SimulationEvent::Paste(InputEvent { timestamp, .. })
+            | SimulationEvent::Mouse(MouseEvent { timestamp, .. })
+            | SimulationEvent::Signal(SignalEvent { timestamp, .. }) => *timestamp += offset,
+            SimulationEvent::WaitBarrier { .. } | SimulationEvent::Sleep(_) => break,
+            SimulationEvent::Marker(_) | SimulationEvent::Resize(_) => (),
+        }
+    }
+}
+
 fn read_line_comment(reader: &mut impl BufRead) {
     let mut buf = Vec::new();
     let _ = reader.read_until(b'\n', &mut buf);
 }
 
+/// Reads the rest of the line, a trailing `\` is allowed, like after the other commands
+fn read_line_text(reader: &mut impl BufRead) -> anyhow::Result<String> {
+    let mut buf = Vec::new();
+    reader
+        .read_until(b'\n', &mut buf)
+        .context("Read until end of line")?;
+    let line = String::from_utf8(buf).context("Expected UTF-8 text")?;
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    let line = line.strip_suffix('\\').unwrap_or(line);
+    Ok(line.to_string())
+}
+
+/// Reads key names separated by spaces until the end of the line
+fn read_keys_line(
+    reader: &mut impl BufRead,
+    variables: Option<&InputVariables>,
+) -> anyhow::Result<Vec<Key>> {
+    let line = expand_variables(variables, read_line_text(reader)?.as_bytes())?;
+    let keys = parse_keys(std::str::from_utf8(&line).context("Expected UTF-8 key names")?)?;
+    ensure!(!keys.is_empty(), "Expected at least one key");
+    Ok(keys)
+}
+
 fn read_duration(reader: &mut impl BufRead) -> anyhow::Result<Duration> {
     let num = read_num(reader)?;
     Ok(Duration::from_micros(num))
 }
 
+fn read_u16(reader: &mut impl BufRead) -> anyhow::Result<u16> {
+    let num = read_num(reader)?;
+    num.try_into().context("Number too large")
+}
+
+fn read_i32(reader: &mut impl BufRead) -> anyhow::Result<i32> {
+    let num = read_num(reader)?;
+    num.try_into().context("Number too large")
+}
+
+fn read_terminal_size(reader: &mut impl BufRead) -> anyhow::Result<TerminalSize> {
+    Ok(TerminalSize {
+        cols: read_u16(reader)?,
+        rows: read_u16(reader)?,
+        xpixel: read_u16(reader)?,
+        ypixel: read_u16(reader)?,
+    })
+}
+
+/// Reads the mouse event of a `c:` input command: `<action>:<col>:<row>:`
+fn read_mouse(reader: &mut impl BufRead) -> anyhow::Result<Mouse> {
+    let action = read_field(reader)?;
+    let action = std::str::from_utf8(&action).context("Expected UTF-8 mouse action")?;
+    Ok(Mouse {
+        action: action.parse()?,
+        col: read_u16(reader).context("Invalid column")?,
+        row: read_u16(reader).context("Invalid row")?,
+    })
+}
+
+/// Reads the signal of a `g:` input command, `<signal>:` or `group:<signal>:` when it is sent to
+/// the foreground process group. Returns the signal and whether it is sent to the group.
+fn read_signal(reader: &mut impl BufRead) -> anyhow::Result<(Signal, bool)> {
+    let mut field = read_field(reader)?;
+    let process_group = field == b"group";
+    if process_group {
+        field = read_field(reader)?;
+    }
+    let name = std::str::from_utf8(&field).context("Expected UTF-8 signal name")?;
+    Ok((parse_signal(name)?, process_group))
+}
+
+/// Reads the arguments of a `w:` input command: the barrier, optionally preceded by
+/// `timeout:<duration>:`
+fn read_wait_barrier(
+    reader: &mut impl BufRead,
+    variables: Option<&InputVariables>,
+) -> anyhow::Result<SimulationEvent> {
+    let (barrier, timeout) = match read_barrier_kind(reader)? {
+        Some(kind) if kind == b"timeout:" => {
+            let timeout = read_input_duration(reader).context("Invalid timeout")?;
+            (read_barrier(reader, variables)?, Some(timeout))
+        }
+        kind => (read_barrier_args(reader, kind, variables)?, None),
+    };
+    Ok(SimulationEvent::WaitBarrier { barrier, timeout })
+}
+
+/// Reads a barrier: `<len>:<bytes>` for an output barrier, or the kind of the barrier followed by
+/// its arguments (`re:<len>:<regex>`, `screen:<len>:<text>`,
+/// `region:<top>:<left>:<bottom>:<right>:<len>:<text>`)
+fn read_barrier(
+    reader: &mut impl BufRead,
+    variables: Option<&InputVariables>,
+) -> anyhow::Result<Barrier> {
+    let kind = read_barrier_kind(reader)?;
+    read_barrier_args(reader, kind, variables)
+}
+
+/// Reads the kind of the barrier including the separator, None for output barriers (starting with
+/// the length of the data)
+fn read_barrier_kind(reader: &mut impl BufRead) -> anyhow::Result<Option<Vec<u8>>> {
+    let buf = reader.fill_buf().context("File read error")?;
+    if buf.first().is_some_and(u8::is_ascii_digit) {
+        return Ok(None);
+    }
+    let mut kind = Vec::new();
+    reader
+        .read_until(b':', &mut kind)
+        .context("Read barrier kind")?;
+    Ok(Some(kind))
+}
+
+fn read_barrier_args(
+    reader: &mut impl BufRead,
+    kind: Option<Vec<u8>>,
+    variables: Option<&InputVariables>,
+) -> anyhow::Result<Barrier> {
+    let Some(kind) = kind else {
+        return Ok(Barrier::Output(expand_variables(
+            variables,
+            &read_data(reader)?,
+        )?));
+    };
+    let read_text = |reader: &mut _| -> anyhow::Result<String> {
+        let text = expand_variables(variables, &read_data(reader)?)?;
+        String::from_utf8(text.to_vec()).context("Expected UTF-8 text")
+    };
+    let barrier = match &kind[..] {
+        b"re:" => {
+            let regex = read_text(reader)?;
+            Barrier::Regex(Regex::new(&regex).context("Invalid regex")?)
+        }
+        b"screen:" => Barrier::Screen(read_text(reader)?),
+        b"region:" => Barrier::Region {
+            top: read_u16(reader)?,
+            left: read_u16(reader)?,
+            bottom: read_u16(reader)?,
+            right: read_u16(reader)?,
+            text: read_text(reader)?,
+        },
+        other => bail!(
+            "Unknown barrier kind {:?}, expected a length, re, screen or region",
+            String::from_utf8_lossy(other)
+        ),
+    };
+    Ok(barrier)
+}
+
 fn read_data(reader: &mut impl BufRead) -> anyhow::Result<Data> {
     let buf_len = read_num(reader)?;
     let mut data = vec![0u8; buf_len as usize];
@@ -250,94 +1451,935 @@ fn read_data(reader: &mut impl BufRead) -> anyhow::Result<Data> {
     Ok(data.into())
 }
 
-fn load_recording_termec_format(
-    mut file: BufReader<File>,
-) -> anyhow::Result<Vec<(Duration, RecordingEvent)>> {
-    let mut events = Vec::new();
-    let mut line_num = 0;
-    loop {
-        let mut cmd = [0u8; 2];
-        match file.read_exact(&mut cmd) {
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
-            Err(e) => bail!("File read error: {e}"),
-            Ok(()) => (),
-        }
-        let err_context = || format!("On line {line_num}");
-        let (timestamp, event) = match &cmd {
-            b"o:" => {
-                let timestamp = read_duration(&mut file).with_context(err_context)?;
-                let data = read_data(&mut file).with_context(err_context)?;
-                (timestamp, RecordingEvent::Output(data))
-            }
-            b"i:" => {
-                let timestamp = read_duration(&mut file).with_context(err_context)?;
-                let data = read_data(&mut file).with_context(err_context)?;
-                (timestamp, RecordingEvent::InputRealized(data))
-            }
-            b"w:" => {
-                let timestamp = read_duration(&mut file).with_context(err_context)?;
-                let data = read_data(&mut file).with_context(err_context)?;
-                (timestamp, RecordingEvent::BarrierUnlocked(data))
-            }
-            b"s:" => {
-                let timestamp = read_duration(&mut file).with_context(err_context)?;
-                let duration = read_duration(&mut file).with_context(err_context)?;
-                (timestamp, RecordingEvent::SleepFinished(duration))
-            }
-            b"m:" => {
-                let timestamp = read_duration(&mut file).with_context(err_context)?;
-                let data = read_data(&mut file).with_context(err_context)?;
-                (timestamp, RecordingEvent::Marker(data))
-            }
-            b"--" => {
-                read_line_comment(&mut file);
-                continue;
+struct TermrecEvents<R> {
+    file: R,
+    line_num: usize,
+}
+
+impl<R: BufRead> TermrecEvents<R> {
+    /// Reads the next event, the metadata entries on the way are stored to `metadata`
+    fn next_event(
+        &mut self,
+        metadata: &mut RecordingMetadata,
+    ) -> anyhow::Result<Option<(Duration, RecordingEvent)>> {
+        let file = &mut self.file;
+        loop {
+            let mut cmd = [0u8; 2];
+            match file.read_exact(&mut cmd) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => bail!("File read error: {e}"),
+                Ok(()) => (),
             }
-            b"\\\n" => {
-                line_num += 1;
-                continue;
+            let result = match &cmd {
+                b"h:" => read_metadata_entry(file, metadata).map(|()| None),
+                b"--" => {
+                    read_line_comment(file);
+                    continue;
+                }
+                b"\\\n" => {
+                    self.line_num += 1;
+                    continue;
+                }
+                b"\n\n" => {
+                    self.line_num += 2;
+                    continue;
+                }
+                cmd => read_event_termrec(cmd, file).map(Some),
+            };
+
+            match result {
+                Ok(Some(event)) => return Ok(Some(event)),
+                Ok(None) => (),
+                // The recording was interrupted while writing the last record
+                Err(e) if is_unexpected_eof(&e) => {
+                    log::warn!("Ignoring truncated record at the end of the recording: {e:#}");
+                    return Ok(None);
+                }
+                Err(e) => return Err(e).context(format!("On line {}", self.line_num)),
             }
-            b"\n\n" => {
-                line_num += 2;
-                continue;
+        }
+    }
+}
+
+fn read_event_termrec(
+    cmd: &[u8; 2],
+    file: &mut impl BufRead,
+) -> anyhow::Result<(Duration, RecordingEvent)> {
+    let event = match cmd {
+        b"o:" => {
+            let timestamp = read_duration(file)?;
+            (timestamp, RecordingEvent::Output(read_data(file)?))
+        }
+        b"i:" => {
+            let timestamp = read_duration(file)?;
+            (timestamp, RecordingEvent::InputRealized(read_data(file)?))
+        }
+        b"w:" => {
+            let timestamp = read_duration(file)?;
+            let description = BarrierDescription::Output(read_data(file)?);
+            (timestamp, RecordingEvent::BarrierUnlocked(description))
+        }
+        b"W:" => {
+            let timestamp = read_duration(file)?;
+            let description = BarrierDescription::Other(read_data(file)?);
+            (timestamp, RecordingEvent::BarrierUnlocked(description))
+        }
+        b"t:" => {
+            let timestamp = read_duration(file)?;
+            let description = BarrierDescription::Output(read_data(file)?);
+            (timestamp, RecordingEvent::BarrierTimedOut(description))
+        }
+        b"T:" => {
+            let timestamp = read_duration(file)?;
+            let description = BarrierDescription::Other(read_data(file)?);
+            (timestamp, RecordingEvent::BarrierTimedOut(description))
+        }
+        b"g:" => {
+            let timestamp = read_duration(file)?;
+            (timestamp, RecordingEvent::SignalSent(read_data(file)?))
+        }
+        b"e:" => {
+            let timestamp = read_duration(file)?;
+            (timestamp, RecordingEvent::Stopped(read_data(file)?))
+        }
+        b"s:" => {
+            let timestamp = read_duration(file)?;
+            (
+                timestamp,
+                RecordingEvent::SleepFinished(read_duration(file)?),
+            )
+        }
+        b"m:" => {
+            let timestamp = read_duration(file)?;
+            (timestamp, RecordingEvent::Marker(read_data(file)?))
+        }
+        b"r:" => {
+            let timestamp = read_duration(file)?;
+            (timestamp, RecordingEvent::Resize(read_terminal_size(file)?))
+        }
+        b"x:" => {
+            let timestamp = read_duration(file)?;
+            let code = read_i32(file)?;
+            (timestamp, RecordingEvent::Exit(ChildExit::Exited(code)))
+        }
+        b"k:" => {
+            let timestamp = read_duration(file)?;
+            let signal = read_i32(file)?;
+            (timestamp, RecordingEvent::Exit(ChildExit::Signaled(signal)))
+        }
+        other => bail!(
+            "Unknown recording command {other:?} ({:?})",
+            String::from_utf8_lossy(other)
+        ),
+    };
+    Ok(event)
+}
+
+/// Saves the recording as an asciicast v2 file (the format of asciinema). Barrier unlocks are
+/// saved as markers, sleeps and the exit of the program are not representable and are dropped.
+pub fn save_recording_asciicast(
+    metadata: &RecordingMetadata,
+    events: &[(Duration, RecordingEvent)],
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(path).context("Failed to open output file")?);
+    write_recording_asciicast(metadata, events, &mut f)
+        .and_then(|()| f.flush().map_err(Into::into))
+        .context("Failed to write to output file")
+}
+
+fn write_recording_asciicast(
+    metadata: &RecordingMetadata,
+    events: &[(Duration, RecordingEvent)],
+    f: &mut impl Write,
+) -> anyhow::Result<()> {
+    let initial_size_index = initial_terminal_size_index(events);
+    let size = initial_terminal_size(events).unwrap_or_default();
+    let mut header = serde_json::json!({
+        "version": 2,
+        "width": size.cols,
+        "height": size.rows,
+    });
+    if let Some(timestamp) = metadata.timestamp {
+        header["timestamp"] = timestamp.into();
+    }
+    if !metadata.env.is_empty() {
+        header["env"] = metadata
+            .env
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone().into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+    }
+    writeln!(f, "{header}")?;
+
+    // Output and input can be split in the middle of an UTF-8 character
+    let mut output = Utf8Decoder::default();
+    let mut input = Utf8Decoder::default();
+    for (i, (timestamp, event)) in events.iter().enumerate() {
+        // Already in the header
+        if Some(i) == initial_size_index {
+            continue;
+        }
+        let (code, data) = match event {
+            RecordingEvent::Output(data) => ("o", output.decode(data)),
+            RecordingEvent::InputRealized(data) => ("i", input.decode(data)),
+            RecordingEvent::Marker(data) => ("m", String::from_utf8_lossy(data).into_owned()),
+            RecordingEvent::BarrierUnlocked(description) => (
+                "m",
+                format!("barrier: {}", String::from_utf8_lossy(description.data())),
+            ),
+            RecordingEvent::BarrierTimedOut(description) => (
+                "m",
+                format!(
+                    "barrier timed out: {}",
+                    String::from_utf8_lossy(description.data())
+                ),
+            ),
+            RecordingEvent::SignalSent(data) => {
+                ("m", format!("signal: {}", String::from_utf8_lossy(data)))
+            }
+            RecordingEvent::Stopped(data) => {
+                ("m", format!("stopped: {}", String::from_utf8_lossy(data)))
             }
-            other => bail!("Unknown recording command {other:?}, line {line_num}"),
+            RecordingEvent::Resize(size) => ("r", format!("{}x{}", size.cols, size.rows)),
+            RecordingEvent::SleepFinished(_) | RecordingEvent::Exit(_) => continue,
         };
+        if data.is_empty() && code != "m" {
+            continue;
+        }
+        let line = serde_json::json!([timestamp.as_secs_f64(), code, data]);
+        writeln!(f, "{line}")?;
+    }
+
+    // A character cut off by the end of the recording
+    let end = events
+        .last()
+        .map(|(timestamp, _)| *timestamp)
+        .unwrap_or_default();
+    for (code, decoder) in [("o", output), ("i", input)] {
+        let data = decoder.finish();
+        if !data.is_empty() {
+            let line = serde_json::json!([end.as_secs_f64(), code, data]);
+            writeln!(f, "{line}")?;
+        }
+    }
+    Ok(())
+}
 
-        events.push((timestamp, event));
+/// Decodes UTF-8 split into multiple chunks, invalid sequences are replaced by U+FFFD
+#[derive(Default)]
+struct Utf8Decoder {
+    incomplete: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    fn decode(&mut self, data: &[u8]) -> String {
+        let mut bytes = std::mem::take(&mut self.incomplete);
+        bytes.extend_from_slice(data);
+
+        let mut result = String::new();
+        let mut rest = &bytes[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    result.push_str(valid);
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    result.push_str(&String::from_utf8_lossy(valid));
+                    match e.error_len() {
+                        Some(len) => {
+                            result.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        None => {
+                            self.incomplete = invalid.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        result
     }
 
-    Ok(events)
+    /// Returns the incomplete sequence left at the end as U+FFFD
+    fn finish(self) -> String {
+        String::from_utf8_lossy(&self.incomplete).into_owned()
+    }
 }
 
-fn load_recording_asciinema_format(
-    file: BufReader<File>,
-) -> anyhow::Result<Vec<(Duration, RecordingEvent)>> {
-    file.lines()
-        .skip(1)
-        .flat_map(|line| {
-            line.map_err(Into::into)
-                .and_then(asciinema_line_to_event)
-                .transpose()
-        })
-        .collect()
+struct AsciicastEvents<R> {
+    lines: std::iter::Enumerate<std::io::Lines<R>>,
+}
+
+impl<R: BufRead> AsciicastEvents<R> {
+    fn open(file: R) -> anyhow::Result<(Self, RecordingMetadata, TerminalSize)> {
+        let mut lines = file.lines().enumerate();
+        let (_, header) = lines.next().context("Empty file")?;
+        let (metadata, size) = asciinema_header(&header.context("Failed to read header")?)
+            .context("Invalid header on line 1")?;
+        Ok((Self { lines }, metadata, size))
+    }
+
+    fn next_event(&mut self) -> anyhow::Result<Option<(Duration, RecordingEvent)>> {
+        for (i, line) in self.lines.by_ref() {
+            let line_num = i + 1;
+            let line = line.with_context(|| format!("Failed to read line {line_num}"))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(event) =
+                asciinema_line_to_event(&line).with_context(|| format!("On line {line_num}"))?
+            {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn asciinema_header(line: &str) -> anyhow::Result<(RecordingMetadata, TerminalSize)> {
+    let header: serde_json::Value = serde_json::from_str(line).context("Failed to parse json")?;
+    let header = header.as_object().context("Expected json object")?;
+
+    let version = header.get("version").and_then(|v| v.as_u64());
+    ensure!(
+        version == Some(2),
+        "Unsupported asciicast version: {version:?}, only version 2 is supported"
+    );
+
+    let dimension = |name: &str| -> anyhow::Result<u16> {
+        let value = header
+            .get(name)
+            .with_context(|| format!("Missing {name:?}"))?;
+        value
+            .as_u64()
+            .and_then(|v| u16::try_from(v).ok())
+            .with_context(|| format!("Invalid {name:?}: {value}"))
+    };
+    let size = TerminalSize {
+        cols: dimension("width")?,
+        rows: dimension("height")?,
+        xpixel: 0,
+        ypixel: 0,
+    };
+
+    let timestamp = header
+        .get("timestamp")
+        .map(|t| t.as_u64().context("Invalid \"timestamp\""))
+        .transpose()?;
+    let env = match header.get("env") {
+        None => Vec::new(),
+        Some(env) => env
+            .as_object()
+            .context("Invalid \"env\", expected json object")?
+            .iter()
+            .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+            .collect(),
+    };
+
+    let metadata = RecordingMetadata {
+        timestamp,
+        terminal_size: Some(size),
+        env,
+        ..Default::default()
+    };
+    Ok((metadata, size))
 }
 
-fn asciinema_line_to_event(line: String) -> anyhow::Result<Option<(Duration, RecordingEvent)>> {
+fn asciinema_line_to_event(line: &str) -> anyhow::Result<Option<(Duration, RecordingEvent)>> {
     let parsed_json: serde_json::Value =
-        serde_json::from_str(&line).context("Failed to parse json")?;
+        serde_json::from_str(line).context("Failed to parse json")?;
     let arr = parsed_json.as_array().context("Expected json array")?;
+    let [timestamp, event, data] = &arr[..] else {
+        bail!("Expected json array of 3 elements, got {}", arr.len());
+    };
 
-    let timestamp = arr[0].as_f64().context("Expected number")?;
-    let event = arr[1].as_str().context("Expected string")?;
-    match event {
-        "m" => return Ok(None), // Marker - ignored/unsupported
-        "o" => (),              // Output
-        "r" => bail!("Resize unsuported"),
-        _ => bail!("Unknown event: {event:?}"),
+    let timestamp = timestamp.as_f64().context("Expected number")?;
+    let timestamp = Duration::try_from_secs_f64(timestamp)
+        .with_context(|| format!("Invalid timestamp: {timestamp}"))?;
+    let event = event.as_str().context("Expected string")?;
+    let data = data.as_str().context("Expected string")?;
+
+    let event = match event {
+        "o" => RecordingEvent::Output(Arc::from(data.as_bytes())),
+        "i" => RecordingEvent::InputRealized(Arc::from(data.as_bytes())),
+        "m" => RecordingEvent::Marker(Arc::from(data.as_bytes())),
+        "r" => {
+            let size = parse_cols_x_rows(data)
+                .with_context(|| format!("Invalid resize event data: {data:?}"))?;
+            RecordingEvent::Resize(size)
+        }
+        _ => {
+            log::warn!("Ignoring unknown asciicast event: {event:?}");
+            return Ok(None);
+        }
+    };
+    Ok(Some((timestamp, event)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_format::{
+        load_input, parse_input_duration, parse_signal, read_barrier, read_keys_line, read_signal,
+        read_typing_speed, read_wait_barrier, save_input_termrec, type_text,
+        validitate_simulation_events, write_event_termrec, write_metadata_termrec,
+        write_recording_asciicast, Barrier, BarrierDescription, ChildExit, Data, InputEvent,
+        InputParser, InputVariables, MouseEvent, RecordingEvent, RecordingMetadata,
+        RecordingReader, SignalEvent, SimulationEvent, TerminalSize, TypingSpeed,
+    };
+    use crate::mouse::{Mouse, MouseAction, MouseButton};
+    use crate::utils::XorShift;
+    use nix::sys::signal::Signal;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn size(cols: u16, rows: u16) -> TerminalSize {
+        TerminalSize {
+            cols,
+            rows,
+            xpixel: 0,
+            ypixel: 0,
+        }
+    }
+
+    fn read_recording(
+        file: &[u8],
+    ) -> anyhow::Result<(RecordingMetadata, Vec<(Duration, RecordingEvent)>)> {
+        let mut reader = RecordingReader::new(file)?;
+        let events = reader.by_ref().collect::<anyhow::Result<_>>()?;
+        Ok((reader.metadata().clone(), events))
+    }
+
+    #[test]
+    fn test_termrec_metadata() {
+        let metadata = RecordingMetadata {
+            termrec_version: Some("0.1.0".to_string()),
+            timestamp: Some(1700000000),
+            hostname: Some("host".to_string()),
+            command: vec!["sh".to_string(), "-c".to_string(), "echo a:b".to_string()],
+            terminal_size: Some(size(80, 24)),
+            env: vec![("TERM".to_string(), "xterm=x".to_string())],
+            input_hash: Some("fnv1a64:0123456789abcdef".to_string()),
+        };
+        let mut file = b"termrec:v2:rec:\\\n".to_vec();
+        write_metadata_termrec(&metadata, &mut file).unwrap();
+        file.extend_from_slice(b"h:future_key:1:x\\\no:5:2:hi\\\n");
+
+        let (loaded_metadata, events) = read_recording(&file[..]).unwrap();
+        assert_eq!(loaded_metadata, metadata);
+        assert_eq!(
+            events,
+            vec![(
+                Duration::from_micros(5),
+                RecordingEvent::Output(Arc::from(&b"hi"[..]))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_load_truncated_termrec() {
+        let output = |d: &[u8]| RecordingEvent::Output(Arc::from(d));
+        let expected = vec![(Duration::from_micros(1), output(b"a"))];
+
+        let file = b"termrec:v2:rec:\\\no:1:1:a\\\no:2:5:bc";
+        let (_, events) = read_recording(&file[..]).unwrap();
+        assert_eq!(events, expected);
+
+        let file = b"termrec:v2:rec:\\\no:1:1:a\\\no:2";
+        let (_, events) = read_recording(&file[..]).unwrap();
+        assert_eq!(events, expected);
+
+        let file = b"termrec:v2:rec:\\\no:1:1:a\\\nh:termrec";
+        let (_, events) = read_recording(&file[..]).unwrap();
+        assert_eq!(events, expected);
+
+        // Not a short read, so the recording is corrupted and not just truncated
+        let file = b"termrec:v2:rec:\\\no:1:1:a\\\no:2:x:bc\\\no:3:1:d\\\n";
+        assert!(read_recording(&file[..]).is_err());
+        let file = b"termrec:v2:rec:\\\no:1:1:a\\\no:2:x:";
+        assert!(read_recording(&file[..]).is_err());
+        let file = b"termrec:v2:rec:\\\no:1:1:a\\\nz:2:1:b";
+        assert!(read_recording(&file[..]).is_err());
+    }
+
+    #[test]
+    fn test_load_asciicast() {
+        let file = concat!(
+            r#"{"version": 2, "width": 100, "height": 30, "timestamp": 1700000000, "env": {"TERM": "xterm-256color"}}"#,
+            "\n",
+            r#"[0.5, "o", "hello"]"#,
+            "\n",
+            r#"[1.0, "i", "q"]"#,
+            "\n",
+            r#"[1.25, "m", "quit"]"#,
+            "\n",
+            r#"[2.0, "r", "120x40"]"#,
+            "\n",
+        );
+        let (metadata, events) = read_recording(file.as_bytes()).unwrap();
+        assert_eq!(
+            metadata,
+            RecordingMetadata {
+                timestamp: Some(1700000000),
+                terminal_size: Some(size(100, 30)),
+                env: vec![("TERM".to_string(), "xterm-256color".to_string())],
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            events,
+            vec![
+                (Duration::ZERO, RecordingEvent::Resize(size(100, 30))),
+                (
+                    Duration::from_millis(500),
+                    RecordingEvent::Output(Arc::from(&b"hello"[..]))
+                ),
+                (
+                    Duration::from_secs(1),
+                    RecordingEvent::InputRealized(Arc::from(&b"q"[..]))
+                ),
+                (
+                    Duration::from_millis(1250),
+                    RecordingEvent::Marker(Arc::from(&b"quit"[..]))
+                ),
+                (
+                    Duration::from_secs(2),
+                    RecordingEvent::Resize(size(120, 40))
+                ),
+            ]
+        );
+
+        let file =
+            "{\"version\": 2, \"width\": 80, \"height\": 24}\n[0.1, \"o\", \"a\"]\n[0.2, \"o\"\n";
+        let error = read_recording(file.as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "On line 3");
+
+        for timestamp in ["1e30", "-1"] {
+            let file = format!(
+                "{{\"version\": 2, \"width\": 80, \"height\": 24}}\n[{timestamp}, \"o\", \"a\"]\n"
+            );
+            let error = read_recording(file.as_bytes()).unwrap_err();
+            assert!(
+                format!("{error:#}").contains("Invalid timestamp"),
+                "{error:#}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_asciicast_roundtrip() {
+        let metadata = RecordingMetadata {
+            timestamp: Some(1700000000),
+            terminal_size: Some(size(90, 20)),
+            env: vec![("SHELL".to_string(), "/bin/sh".to_string())],
+            ..Default::default()
+        };
+        let data = |d: &[u8]| Arc::from(d);
+        let events = vec![
+            (Duration::ZERO, RecordingEvent::Resize(size(90, 20))),
+            (
+                Duration::from_millis(1),
+                RecordingEvent::InputRealized(data(b"x")),
+            ),
+            // "ž" split into two output events
+            (
+                Duration::from_millis(2),
+                RecordingEvent::Output(data(b"a\xc5")),
+            ),
+            (
+                Duration::from_millis(3),
+                RecordingEvent::Output(data(b"\xbe")),
+            ),
+            (
+                Duration::from_millis(4),
+                RecordingEvent::BarrierUnlocked(BarrierDescription::Output(data(b"a"))),
+            ),
+            (
+                Duration::from_millis(5),
+                RecordingEvent::Resize(size(91, 21)),
+            ),
+            // "€" cut off by the end of the recording
+            (
+                Duration::from_millis(5),
+                RecordingEvent::Output(data(b"b\xe2\x82")),
+            ),
+            (
+                Duration::from_millis(6),
+                RecordingEvent::Exit(ChildExit::Exited(0)),
+            ),
+        ];
+
+        let mut file = Vec::new();
+        write_recording_asciicast(&metadata, &events, &mut file).unwrap();
+        let (loaded_metadata, loaded_events) = read_recording(&file[..]).unwrap();
+        assert_eq!(loaded_metadata, metadata);
+        assert_eq!(
+            loaded_events,
+            vec![
+                (Duration::ZERO, RecordingEvent::Resize(size(90, 20))),
+                (
+                    Duration::from_millis(1),
+                    RecordingEvent::InputRealized(data(b"x"))
+                ),
+                (Duration::from_millis(2), RecordingEvent::Output(data(b"a"))),
+                (
+                    Duration::from_millis(3),
+                    RecordingEvent::Output(data("ž".as_bytes()))
+                ),
+                (
+                    Duration::from_millis(4),
+                    RecordingEvent::Marker(data(b"barrier: a"))
+                ),
+                (
+                    Duration::from_millis(5),
+                    RecordingEvent::Resize(size(91, 21))
+                ),
+                (Duration::from_millis(5), RecordingEvent::Output(data(b"b"))),
+                (
+                    Duration::from_millis(6),
+                    RecordingEvent::Output(data("\u{FFFD}".as_bytes()))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_barriers() {
+        let variables = InputVariables::default();
+        let file = b"5:a:b:cre:3:a+bscreen:4:hellregion:1:2:3:40:2:okmystery:1:x";
+        let mut reader = &file[..];
+        let mut descriptions = Vec::new();
+        for _ in 0..4 {
+            let barrier = read_barrier(&mut reader, Some(&variables)).unwrap();
+            let description = barrier.description();
+            assert_eq!(
+                Barrier::from_description(&description)
+                    .unwrap()
+                    .description(),
+                description
+            );
+            descriptions.push(String::from_utf8(description.data().to_vec()).unwrap());
+        }
+        assert_eq!(
+            descriptions,
+            ["a:b:c", "re:a+b", "screen:hell", "region:1:2:3:40:ok"]
+        );
+        assert!(read_barrier(&mut reader, Some(&variables)).is_err());
+        assert!(read_barrier(&mut &b"re:2:a("[..], Some(&variables)).is_err());
+
+        let event =
+            read_wait_barrier(&mut &b"timeout:1500:screen:1:x"[..], Some(&variables)).unwrap();
+        assert!(matches!(
+            event,
+            SimulationEvent::WaitBarrier { barrier: Barrier::Screen(text), timeout }
+                if text == "x" && timeout == Some(Duration::from_micros(1500))
+        ));
+        let event = read_wait_barrier(&mut &b"1:x"[..], Some(&variables)).unwrap();
+        assert!(matches!(
+            event,
+            SimulationEvent::WaitBarrier {
+                barrier: Barrier::Output(_),
+                timeout: None
+            }
+        ));
+
+        // Output that looks like the other kinds is still an output barrier
+        let needle: Data = Arc::from(&b"re:a+b"[..]);
+        assert!(matches!(
+            Barrier::from_description(&BarrierDescription::Output(needle.clone())),
+            Ok(Barrier::Output(parsed)) if parsed == needle
+        ));
+        let description = BarrierDescription::Other(Arc::from(&b"region:1:x"[..]));
+        assert!(Barrier::from_description(&description).is_err());
+
+        // The kind is kept in the recording
+        let events = [
+            RecordingEvent::BarrierUnlocked(BarrierDescription::Output(needle.clone())),
+            RecordingEvent::BarrierUnlocked(BarrierDescription::Other(needle.clone())),
+            RecordingEvent::BarrierTimedOut(BarrierDescription::Output(needle.clone())),
+            RecordingEvent::BarrierTimedOut(BarrierDescription::Other(needle)),
+        ];
+        let mut file = b"termrec:v2:rec:\\\n".to_vec();
+        for event in &events {
+            write_event_termrec(&mut file, Duration::ZERO, event).unwrap();
+        }
+        let (_, read) = read_recording(&file).unwrap();
+        let read: Vec<RecordingEvent> = read.into_iter().map(|(_, event)| event).collect();
+        assert_eq!(read, events);
+    }
+
+    #[test]
+    fn test_read_keys_line() {
+        let variables = InputVariables::default();
+        let mut reader = &b"C-c  Up Enter\\\nF5\n\\\n"[..];
+        let keys = read_keys_line(&mut reader, Some(&variables)).unwrap();
+        let names: Vec<String> = keys.iter().map(ToString::to_string).collect();
+        assert_eq!(names, ["C-c", "Up", "Enter"]);
+        assert_eq!(
+            read_keys_line(&mut reader, Some(&variables)).unwrap().len(),
+            1
+        );
+        assert!(read_keys_line(&mut reader, Some(&variables)).is_err());
+    }
+
+    #[test]
+    fn test_input_durations() {
+        let micros = |s| parse_input_duration(s).unwrap().as_micros();
+        assert_eq!(micros("1500"), 1500);
+        assert_eq!(micros("150ms"), 150_000);
+        assert_eq!(micros("1m2.5s"), 62_500_000);
+        assert!(parse_input_duration("1.5").is_err());
+        assert!(parse_input_duration("").is_err());
+        assert!(parse_input_duration("2x").is_err());
+    }
+
+    #[test]
+    fn test_typing() {
+        let mut reader = &b"cps:20:jitter:10ms:5:"[..];
+        let speed = read_typing_speed(&mut reader, TypingSpeed::default()).unwrap();
+        assert_eq!(speed.delay, Some(Duration::from_millis(50)));
+        assert_eq!(speed.jitter, Duration::from_millis(10));
+        assert_eq!(reader, b"5:");
+        assert!(read_typing_speed(&mut &b"cps:0:1:"[..], speed).is_err());
+        assert!(read_typing_speed(&mut &b"speed:1:1:"[..], speed).is_err());
+
+        let mut rng = XorShift::new(1);
+        let mut events = vec![SimulationEvent::Input(InputEvent {
+            timestamp: Duration::from_secs(1),
+            data: Arc::from(&b"x"[..]),
+        })];
+        type_text(&mut events, "až", speed, &mut rng).unwrap();
+        let typed: Vec<(Duration, &[u8])> = events
+            .iter()
+            .map(|event| match event {
+                SimulationEvent::Input(InputEvent { timestamp, data }) => (*timestamp, &data[..]),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(typed[1].1, b"a");
+        assert_eq!(typed[2].1, "ž".as_bytes());
+        for pair in typed.windows(2) {
+            let delay = pair[1].0 - pair[0].0;
+            assert!(delay >= Duration::from_millis(40) && delay <= Duration::from_millis(60));
+        }
+        validitate_simulation_events(&events).unwrap();
+
+        // Typing continues after the previous input, so earlier input is out of order
+        events.push(SimulationEvent::Input(InputEvent {
+            timestamp: Duration::from_secs(1),
+            data: Arc::from(&b"y"[..]),
+        }));
+        assert!(validitate_simulation_events(&events).is_err());
+
+        assert!(read_typing_speed(&mut &b"cps:1e-20:"[..], TypingSpeed::default()).is_err());
+        let speed = read_typing_speed(&mut &b"jitter:1ms:"[..], TypingSpeed::default()).unwrap();
+        assert!(type_text(&mut events, "a", speed, &mut rng).is_err());
     }
-    let data = arr[2].as_str().context("Expected string")?.to_string();
 
-    let event = RecordingEvent::Output(Arc::from(data.as_bytes()));
-    Ok(Some((Duration::from_secs_f64(timestamp), event)))
+    #[test]
+    fn test_input_variables() {
+        let variables = InputVariables::new(&[("NAME".to_string(), "world".to_string())]);
+        let expand = |s: &str| variables.expand(s.as_bytes()).map(|data| data.to_vec());
+        assert_eq!(expand("hello ${NAME}!").unwrap(), b"hello world!");
+        assert_eq!(
+            expand("$${NAME} ${NAME} $NAME").unwrap(),
+            b"${NAME} world $NAME"
+        );
+        assert!(expand("${NAME").is_err());
+        assert!(expand("${1X}").is_err());
+        assert!(expand("${TERMREC_TEST_UNDEFINED}").is_err());
+        assert!(InputVariables::default().expand(b"${HOME}").is_err());
+    }
+
+    #[test]
+    fn test_save_input_escapes_variables() {
+        let path = std::env::temp_dir().join(format!("termrec-test-{}.inp", std::process::id()));
+        let events = vec![
+            SimulationEvent::Input(InputEvent {
+                timestamp: Duration::from_millis(1),
+                data: Arc::from(&b"echo ${HOME} $${X} $"[..]),
+            }),
+            SimulationEvent::WaitBarrier {
+                barrier: Barrier::Output(Arc::from(&b"${HOME}"[..])),
+                timeout: None,
+            },
+            SimulationEvent::Marker(Arc::from(&b"{${"[..])),
+        ];
+        save_input_termrec(&events, &path).unwrap();
+        let loaded = load_input(&path, &InputVariables::default());
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert!(matches!(
+            &loaded[0],
+            SimulationEvent::Input(InputEvent { data, .. }) if &data[..] == b"echo ${HOME} $${X} $"
+        ));
+        assert!(matches!(
+            &loaded[1],
+            SimulationEvent::WaitBarrier { barrier: Barrier::Output(needle), .. }
+                if &needle[..] == b"${HOME}"
+        ));
+        assert!(matches!(&loaded[2], SimulationEvent::Marker(data) if &data[..] == b"{${"));
+    }
+
+    #[test]
+    fn test_load_input_v1_keeps_variables() {
+        let path = std::env::temp_dir().join(format!("termrec-test-v1-{}.inp", std::process::id()));
+        let variables = InputVariables::new(&[("X".to_string(), "y".to_string())]);
+        let load = |header: &str| {
+            std::fs::write(&path, format!("{header}\\\ni:1ms:4:${{X}}\\\n")).unwrap();
+            load_input(&path, &variables)
+        };
+        let v1 = load("termrec:v1:inp:");
+        let v2 = load("termrec:v2:inp:");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            &v1.unwrap()[..],
+            [SimulationEvent::Input(InputEvent { data, .. })] if &data[..] == b"${X}"
+        ));
+        assert!(matches!(
+            &v2.unwrap()[..],
+            [SimulationEvent::Input(InputEvent { data, .. })] if &data[..] == b"y"
+        ));
+    }
+
+    #[test]
+    fn test_repeat() {
+        let variables = InputVariables::new(&[("N".to_string(), "3".to_string())]);
+        let mut parser = InputParser {
+            variables: &variables,
+            variables_enabled: true,
+            typing_speed: TypingSpeed::default(),
+            jitter_rng: XorShift::new(1),
+            files: Vec::new(),
+        };
+        let file = b"d:delay:1ms:\\\nrepeat ${N} {\\\nt:2:ab\\\nrepeat 0 {\\\ni:0:1:x\\\n}\\\n}\\\nw:1:b\\\n";
+        let mut events = Vec::new();
+        let mut line_num = 0;
+        assert!(!parser
+            .parse(&mut &file[..], &mut line_num, &mut events)
+            .unwrap());
+        assert_eq!(line_num, 8);
+        let typed: Vec<(u128, &[u8])> = events
+            .iter()
+            .filter_map(|event| match event {
+                SimulationEvent::Input(InputEvent { timestamp, data }) => {
+                    Some((timestamp.as_millis(), &data[..]))
+                }
+                _ => None,
+            })
+            .collect();
+        let expected: Vec<(u128, &[u8])> = (1..=6)
+            .map(|ms| (ms, if ms % 2 == 1 { &b"a"[..] } else { &b"b"[..] }))
+            .collect();
+        assert_eq!(typed, expected);
+        assert!(matches!(
+            events.last(),
+            Some(SimulationEvent::WaitBarrier { .. })
+        ));
+
+        // Each repetition continues after the input of the previous one, until a barrier
+        let file = b"repeat 3 {\\\ni:10ms:1:a\\\ni:20ms:1:b\\\n}\\\nw:1:y\\\nrepeat 2 {\\\ni:5ms:1:c\\\nw:1:x\\\ni:1ms:1:d\\\n}\\\n";
+        let mut events = Vec::new();
+        parser.parse(&mut &file[..], &mut 0, &mut events).unwrap();
+        validitate_simulation_events(&events).unwrap();
+        let inputs: Vec<(u128, &[u8])> = events
+            .iter()
+            .filter_map(|event| match event {
+                SimulationEvent::Input(InputEvent { timestamp, data }) => {
+                    Some((timestamp.as_millis(), &data[..]))
+                }
+                _ => None,
+            })
+            .collect();
+        let expected: [(u128, &[u8]); 10] = [
+            (10, b"a"),
+            (20, b"b"),
+            (30, b"a"),
+            (40, b"b"),
+            (50, b"a"),
+            (60, b"b"),
+            (5, b"c"),
+            (1, b"d"),
+            (6, b"c"),
+            (1, b"d"),
+        ];
+        assert_eq!(inputs, expected);
+
+        for invalid in [
+            &b"repeat 2 {\\\n"[..],
+            b"repeat x {\\\n}\\\n",
+            b"unknown\\\n",
+        ] {
+            assert!(parser
+                .parse(&mut &invalid[..], &mut 0, &mut Vec::new())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_signals() {
+        assert_eq!(parse_signal("SIGTSTP").unwrap(), Signal::SIGTSTP);
+        assert_eq!(parse_signal("int").unwrap(), Signal::SIGINT);
+        assert_eq!(parse_signal("15").unwrap(), Signal::SIGTERM);
+        assert!(parse_signal("SIGFOO").is_err());
+        assert!(parse_signal("0").is_err());
+
+        let mut reader = &b"USR1:group:SIGCONT:"[..];
+        assert_eq!(read_signal(&mut reader).unwrap(), (Signal::SIGUSR1, false));
+        let (signal, process_group) = read_signal(&mut reader).unwrap();
+        let event = SignalEvent {
+            timestamp: Duration::ZERO,
+            signal,
+            process_group,
+        };
+        assert_eq!(&event.description()[..], b"group:SIGCONT");
+        let parsed = SignalEvent::from_description(Duration::ZERO, &event.description()).unwrap();
+        assert_eq!(
+            (parsed.signal, parsed.process_group),
+            (Signal::SIGCONT, true)
+        );
+    }
+
+    #[test]
+    fn test_paste_and_mouse() {
+        let variables = InputVariables::default();
+        let mut parser = InputParser {
+            variables: &variables,
+            variables_enabled: true,
+            typing_speed: TypingSpeed::default(),
+            jitter_rng: XorShift::new(1),
+            files: Vec::new(),
+        };
+        let file = b"p:1ms:4:a\nb\n\\\nc:2ms:drag-right:3:4:\\\n";
+        let mut events = Vec::new();
+        parser.parse(&mut &file[..], &mut 0, &mut events).unwrap();
+        assert!(matches!(
+            &events[0],
+            SimulationEvent::Paste(InputEvent { timestamp, data })
+                if timestamp.as_millis() == 1 && &data[..] == b"a\nb\n"
+        ));
+        assert!(matches!(
+            events[1],
+            SimulationEvent::Mouse(MouseEvent {
+                mouse: Mouse {
+                    action: MouseAction::Drag(MouseButton::Right),
+                    col: 3,
+                    row: 4,
+                },
+                ..
+            })
+        ));
+
+        for invalid in [&b"p:0:path:1:x"[..], b"p:0:file:0:", b"c:0:click:3:"] {
+            assert!(parser
+                .parse(&mut &invalid[..], &mut 0, &mut Vec::new())
+                .is_err());
+        }
+    }
 }
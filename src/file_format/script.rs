@@ -0,0 +1,363 @@
+//! The format of util-linux `script --log-timing` (replayed by `scriptreplay`): a typescript
+//! file with the raw data and a timing file describing the chunks of the data.
+//!
+//! The classic timing format only contains the output, each line is `<delay> <bytes>`. The
+//! advanced (multi-stream) format prefixes each line with the type: `O`utput, `I`nput,
+//! `S`ignal (`S <delay> SIGWINCH ROWS=<rows> COLS=<cols>`) and `H`eader (`H <delay> <name>
+//! <value>`). Input and output can be stored in the same (`--log-io`) or separate files.
+//! The delays are in seconds relative to the previous line.
+
+use crate::file_format::{
+    initial_terminal_size_index, ChildExit, Data, RecordingEvent, RecordingMetadata, TerminalSize,
+};
+use anyhow::{bail, Context};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+const LOG_HEADER_START: &[u8] = b"Script started";
+
+/// Loads a recording from the timing file and the typescript. `input_log` is the file with
+/// the input when it is logged separately from the output (`--log-in`).
+pub fn load_recording_script(
+    timing: &Path,
+    log: &Path,
+    input_log: Option<&Path>,
+) -> anyhow::Result<(RecordingMetadata, Vec<(Duration, RecordingEvent)>)> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("Failed to open {}", path.display()))
+    };
+    read_script(open(timing)?, open(log)?, input_log.map(open).transpose()?)
+}
+
+fn read_script(
+    timing: impl BufRead,
+    mut log: impl BufRead,
+    mut input_log: Option<impl BufRead>,
+) -> anyhow::Result<(RecordingMetadata, Vec<(Duration, RecordingEvent)>)> {
+    let mut metadata = RecordingMetadata::default();
+    let mut cols = None;
+    let mut rows = None;
+
+    let mut set_header = |metadata: &mut RecordingMetadata, name: &str, value: &str| match name {
+        "COLUMNS" => cols = value.parse().ok(),
+        "LINES" => rows = value.parse().ok(),
+        "TERM" | "SHELL" => match metadata.env.iter_mut().find(|(n, _)| n == name) {
+            Some((_, existing)) => *existing = value.to_string(),
+            None => metadata.env.push((name.to_string(), value.to_string())),
+        },
+        _ => (),
+    };
+
+    for (name, value) in skip_log_header(&mut log)? {
+        set_header(&mut metadata, &name, &value);
+    }
+    if let Some(input_log) = &mut input_log {
+        skip_log_header(input_log)?;
+    }
+
+    let mut events = Vec::new();
+    let mut time = Duration::ZERO;
+    for (line_num, line) in timing.lines().enumerate() {
+        let line_num = line_num + 1;
+        let line = line.with_context(|| format!("Failed to read timing line {line_num}"))?;
+        let err_context = || format!("On timing line {line_num}: {line:?}");
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let advanced = line.starts_with(|c: char| c.is_ascii_alphabetic());
+        let (kind, rest) = if advanced {
+            line.split_once(' ').with_context(err_context)?
+        } else {
+            ("O", line)
+        };
+        let (delay, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        time += parse_seconds(delay).with_context(err_context)?;
+
+        let event = match kind {
+            "O" => {
+                let len = rest.parse().with_context(err_context)?;
+                RecordingEvent::Output(read_chunk(&mut log, len).with_context(err_context)?)
+            }
+            "I" => {
+                let len = rest.parse().with_context(err_context)?;
+                let data = match &mut input_log {
+                    Some(input_log) => read_chunk(input_log, len),
+                    None => read_chunk(&mut log, len),
+                };
+                RecordingEvent::InputRealized(data.with_context(err_context)?)
+            }
+            "S" => match parse_sigwinch(rest) {
+                Some(size) => RecordingEvent::Resize(size),
+                None => continue,
+            },
+            "H" => {
+                let (name, value) = rest.split_once(' ').unwrap_or((rest, ""));
+                if name == "EXIT_CODE" {
+                    let code = value.parse().with_context(err_context)?;
+                    RecordingEvent::Exit(ChildExit::Exited(code))
+                } else {
+                    set_header(&mut metadata, name, value);
+                    continue;
+                }
+            }
+            _ => bail!("Unknown timing entry type {kind:?} on timing line {line_num}"),
+        };
+        events.push((time, event));
+    }
+
+    if let (Some(cols), Some(rows)) = (cols, rows) {
+        let size = TerminalSize {
+            cols,
+            rows,
+            xpixel: 0,
+            ypixel: 0,
+        };
+        metadata.terminal_size = Some(size);
+        events.insert(0, (Duration::ZERO, RecordingEvent::Resize(size)));
+    }
+    Ok((metadata, events))
+}
+
+/// Skips the `Script started on ... [NAME="value" ...]` line at the start of the log, returns
+/// the values from it
+fn skip_log_header(log: &mut impl BufRead) -> anyhow::Result<Vec<(String, String)>> {
+    if !log
+        .fill_buf()
+        .context("Failed to read log")?
+        .starts_with(LOG_HEADER_START)
+    {
+        return Ok(Vec::new());
+    }
+    let mut line = Vec::new();
+    log.read_until(b'\n', &mut line)
+        .context("Failed to read log")?;
+    let line = String::from_utf8_lossy(&line);
+
+    let Some((_, values)) = line.split_once('[') else {
+        return Ok(Vec::new());
+    };
+    Ok(values
+        .split('"')
+        .collect::<Vec<_>>()
+        .chunks_exact(2)
+        .filter_map(|pair| {
+            let name = pair[0].trim().strip_suffix('=')?;
+            Some((name.to_string(), pair[1].to_string()))
+        })
+        .collect())
+}
+
+fn read_chunk(reader: &mut impl Read, len: usize) -> anyhow::Result<Data> {
+    let mut data = vec![0u8; len];
+    reader
+        .read_exact(&mut data)
+        .context("Log is shorter than the timing file describes")?;
+    Ok(Arc::from(data))
+}
+
+fn parse_sigwinch(signal: &str) -> Option<TerminalSize> {
+    let mut words = signal.split_whitespace();
+    if words.next()? != "SIGWINCH" {
+        return None;
+    }
+    let mut cols = None;
+    let mut rows = None;
+    for word in words {
+        match word.split_once('=')? {
+            ("ROWS", value) => rows = value.parse().ok(),
+            ("COLS", value) => cols = value.parse().ok(),
+            _ => (),
+        }
+    }
+    Some(TerminalSize {
+        cols: cols?,
+        rows: rows?,
+        xpixel: 0,
+        ypixel: 0,
+    })
+}
+
+/// Parses seconds with a decimal fraction, without the rounding of floats
+fn parse_seconds(seconds: &str) -> anyhow::Result<Duration> {
+    let (secs, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|c| c.is_ascii_digit()) {
+        bail!("Invalid delay: {seconds:?}");
+    }
+    let secs = secs.parse().context("Invalid delay")?;
+    let nanos = format!("{fraction:0<9}").parse().context("Invalid delay")?;
+    Ok(Duration::new(secs, nanos))
+}
+
+/// Saves the recording as a timing file and a typescript. The advanced format also contains the
+/// input (in the same file as the output, like `--log-io`), resizes and the exit code, the
+/// classic format only the output. Markers and barriers are dropped.
+pub fn save_recording_script(
+    metadata: &RecordingMetadata,
+    events: &[(Duration, RecordingEvent)],
+    timing: &Path,
+    log: &Path,
+    advanced: bool,
+) -> anyhow::Result<()> {
+    let create = |path: &Path| {
+        File::create(path)
+            .map(BufWriter::new)
+            .with_context(|| format!("Failed to open {}", path.display()))
+    };
+    let mut timing_file = create(timing)?;
+    let mut log_file = create(log)?;
+    write_script(metadata, events, &mut timing_file, &mut log_file, advanced)
+        .and_then(|()| Ok(timing_file.flush().and(log_file.flush())?))
+        .context("Failed to write to output file")
+}
+
+fn write_script(
+    metadata: &RecordingMetadata,
+    events: &[(Duration, RecordingEvent)],
+    timing: &mut impl Write,
+    log: &mut impl Write,
+    advanced: bool,
+) -> anyhow::Result<()> {
+    let initial_size_index = initial_terminal_size_index(events);
+    let size = match initial_size_index.map(|i| &events[i].1) {
+        Some(RecordingEvent::Resize(size)) => *size,
+        _ => TerminalSize::default(),
+    };
+
+    let mut headers = metadata.env.clone();
+    headers.push(("COLUMNS".to_string(), size.cols.to_string()));
+    headers.push(("LINES".to_string(), size.rows.to_string()));
+
+    write!(log, "Script started [")?;
+    for (i, (name, value)) in headers.iter().enumerate() {
+        let separator = if i == 0 { "" } else { " " };
+        write!(log, "{separator}{name}=\"{value}\"")?;
+    }
+    writeln!(log, "]")?;
+    if advanced {
+        for (name, value) in &headers {
+            writeln!(timing, "H 0.000000 {name} {value}")?;
+        }
+    }
+
+    let mut last_timestamp = Duration::ZERO;
+    for (i, (timestamp, event)) in events.iter().enumerate() {
+        if Some(i) == initial_size_index {
+            continue;
+        }
+        let delay = timestamp.saturating_sub(last_timestamp);
+        let delay = format!("{}.{:06}", delay.as_secs(), delay.subsec_micros());
+        match event {
+            RecordingEvent::Output(data) if advanced => {
+                writeln!(timing, "O {delay} {}", data.len())?;
+                log.write_all(data)?;
+            }
+            RecordingEvent::Output(data) => {
+                writeln!(timing, "{delay} {}", data.len())?;
+                log.write_all(data)?;
+            }
+            RecordingEvent::InputRealized(data) if advanced => {
+                writeln!(timing, "I {delay} {}", data.len())?;
+                log.write_all(data)?;
+            }
+            RecordingEvent::Resize(size) if advanced => {
+                writeln!(
+                    timing,
+                    "S {delay} SIGWINCH ROWS={} COLS={}",
+                    size.rows, size.cols
+                )?;
+            }
+            RecordingEvent::Exit(exit) if advanced => {
+                writeln!(timing, "H {delay} EXIT_CODE {}", exit.code())?;
+            }
+            _ => continue,
+        }
+        last_timestamp = *timestamp;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_format::script::{read_script, write_script};
+    use crate::file_format::{ChildExit, RecordingEvent, RecordingMetadata, TerminalSize};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_script_roundtrip() {
+        let data = |d: &[u8]| Arc::from(d);
+        let size = |cols, rows| TerminalSize {
+            cols,
+            rows,
+            xpixel: 0,
+            ypixel: 0,
+        };
+        let metadata = RecordingMetadata {
+            terminal_size: Some(size(100, 30)),
+            env: vec![("TERM".to_string(), "xterm".to_string())],
+            ..Default::default()
+        };
+        let events = vec![
+            (Duration::ZERO, RecordingEvent::Resize(size(100, 30))),
+            (
+                Duration::from_micros(1),
+                RecordingEvent::Output(data(b"$ ")),
+            ),
+            (
+                Duration::from_millis(20),
+                RecordingEvent::InputRealized(data(b"l")),
+            ),
+            (
+                Duration::from_millis(30),
+                RecordingEvent::Marker(data(b"m")),
+            ),
+            (
+                Duration::from_millis(40),
+                RecordingEvent::Resize(size(90, 20)),
+            ),
+            (
+                Duration::from_secs(2),
+                RecordingEvent::Output(data(b"l\r\n")),
+            ),
+            (
+                Duration::from_secs(3),
+                RecordingEvent::Exit(ChildExit::Exited(1)),
+            ),
+        ];
+
+        let mut timing = Vec::new();
+        let mut log = Vec::new();
+        write_script(&metadata, &events, &mut timing, &mut log, true).unwrap();
+        let (loaded_metadata, loaded_events) =
+            read_script(&timing[..], &log[..], None::<&[u8]>).unwrap();
+        assert_eq!(loaded_metadata, metadata);
+        let expected: Vec<_> = events
+            .iter()
+            .filter(|(_, event)| !matches!(event, RecordingEvent::Marker(_)))
+            .cloned()
+            .collect();
+        assert_eq!(loaded_events, expected);
+
+        // The classic format contains only the output
+        let mut timing = Vec::new();
+        let mut log = Vec::new();
+        write_script(&metadata, &events, &mut timing, &mut log, false).unwrap();
+        assert_eq!(timing, b"0.000001 2\n1.999999 3\n");
+        let (_, loaded_events) = read_script(&timing[..], &log[..], None::<&[u8]>).unwrap();
+        assert_eq!(
+            loaded_events,
+            vec![
+                expected[0].clone(),
+                expected[1].clone(),
+                expected[4].clone()
+            ]
+        );
+    }
+}
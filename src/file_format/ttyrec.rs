@@ -0,0 +1,130 @@
+//! The ttyrec format (used by ttyrec/ttyplay): a sequence of records, each consisting of a
+//! header of three little endian u32 (seconds, microseconds, data length) followed by the data.
+//! It only contains the output of the program, the timestamps are wall-clock time.
+
+use crate::file_format::{RecordingEvent, RecordingMetadata};
+use anyhow::{bail, Context};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub fn load_recording_ttyrec(
+    path: &Path,
+) -> anyhow::Result<(RecordingMetadata, Vec<(Duration, RecordingEvent)>)> {
+    let file = File::open(path).context("Failed to open recording")?;
+    read_ttyrec(BufReader::new(file))
+}
+
+fn read_ttyrec(
+    mut reader: impl Read,
+) -> anyhow::Result<(RecordingMetadata, Vec<(Duration, RecordingEvent)>)> {
+    let mut start = None;
+    let mut events = Vec::new();
+    for record_num in 0.. {
+        let mut header = [0u8; 12];
+        match reader.read_exact(&mut header) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => bail!("File read error: {e}"),
+            Ok(()) => (),
+        }
+        let field = |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+        let (sec, usec, len) = (field(0), field(1), field(2));
+        if usec >= 1_000_000 {
+            bail!("Invalid microseconds {usec} in record {record_num}, not a ttyrec file?");
+        }
+
+        let mut data = vec![0u8; len as usize];
+        reader
+            .read_exact(&mut data)
+            .with_context(|| format!("Truncated record {record_num}"))?;
+
+        let time = Duration::new(sec.into(), usec * 1000);
+        let start = *start.get_or_insert(time);
+        let timestamp = time.checked_sub(start).with_context(|| {
+            format!("Record {record_num} has a timestamp before the start of the recording")
+        })?;
+        events.push((timestamp, RecordingEvent::Output(Arc::from(data))));
+    }
+
+    let metadata = RecordingMetadata {
+        timestamp: start.map(|start| start.as_secs()),
+        ..Default::default()
+    };
+    Ok((metadata, events))
+}
+
+/// Saves the output of the recording in the ttyrec format, other events are dropped
+pub fn save_recording_ttyrec(
+    metadata: &RecordingMetadata,
+    events: &[(Duration, RecordingEvent)],
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut f = BufWriter::new(File::create(path).context("Failed to open output file")?);
+    write_ttyrec(metadata, events, &mut f)
+        .and_then(|()| f.flush().map_err(Into::into))
+        .context("Failed to write to output file")
+}
+
+fn write_ttyrec(
+    metadata: &RecordingMetadata,
+    events: &[(Duration, RecordingEvent)],
+    f: &mut impl Write,
+) -> anyhow::Result<()> {
+    let start = Duration::from_secs(metadata.timestamp.unwrap_or(0));
+    for (timestamp, event) in events {
+        let RecordingEvent::Output(data) = event else {
+            continue;
+        };
+        let time = start + *timestamp;
+        let sec: u32 = time.as_secs().try_into().context("Timestamp too large")?;
+        let len: u32 = data.len().try_into().context("Output too large")?;
+        f.write_all(&sec.to_le_bytes())?;
+        f.write_all(&time.subsec_micros().to_le_bytes())?;
+        f.write_all(&len.to_le_bytes())?;
+        f.write_all(data)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_format::ttyrec::{read_ttyrec, write_ttyrec};
+    use crate::file_format::{RecordingEvent, RecordingMetadata};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ttyrec_roundtrip() {
+        let metadata = RecordingMetadata {
+            timestamp: Some(1700000000),
+            ..Default::default()
+        };
+        let output = |d: &[u8]| RecordingEvent::Output(Arc::from(d));
+        let events = vec![
+            (Duration::ZERO, output(b"$ ")),
+            (
+                Duration::from_micros(1500),
+                RecordingEvent::Marker(Arc::from(&b"m"[..])),
+            ),
+            (Duration::from_micros(2_000_001), output(b"hello\r\n")),
+        ];
+
+        let mut file = Vec::new();
+        write_ttyrec(&metadata, &events, &mut file).unwrap();
+        assert_eq!(file.len(), 2 * 12 + 2 + 7);
+
+        let (loaded_metadata, loaded_events) = read_ttyrec(&file[..]).unwrap();
+        assert_eq!(loaded_metadata, metadata);
+        assert_eq!(
+            loaded_events,
+            vec![
+                (Duration::ZERO, output(b"$ ")),
+                (Duration::from_micros(2_000_001), output(b"hello\r\n")),
+            ]
+        );
+
+        assert!(read_ttyrec(&file[..file.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Symbolic key names used by the `k:` input command (e.g. `Enter`, `C-c`, `M-x`, `S-Up`, `F5`)
+//! and the byte sequences the keys and pasted text send, the same as xterm.
+
+use anyhow::{bail, Context};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyCode {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Space,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    /// F1 to F12
+    F(u8),
+}
+
+/// Names of the keys other than characters and function keys, the first name is the canonical one
+const KEY_NAMES: &[(KeyCode, &[&str])] = &[
+    (KeyCode::Enter, &["Enter", "Return"]),
+    (KeyCode::Tab, &["Tab"]),
+    (KeyCode::Backspace, &["Backspace", "BSpace"]),
+    (KeyCode::Escape, &["Escape", "Esc"]),
+    (KeyCode::Space, &["Space"]),
+    (KeyCode::Up, &["Up"]),
+    (KeyCode::Down, &["Down"]),
+    (KeyCode::Left, &["Left"]),
+    (KeyCode::Right, &["Right"]),
+    (KeyCode::Home, &["Home"]),
+    (KeyCode::End, &["End"]),
+    (KeyCode::PageUp, &["PageUp", "PgUp"]),
+    (KeyCode::PageDown, &["PageDown", "PgDn"]),
+    (KeyCode::Insert, &["Insert", "Ins"]),
+    (KeyCode::Delete, &["Delete", "Del"]),
+    (KeyCode::Char('\\'), &["Backslash"]),
+];
+
+/// A key press, optionally with modifiers: `C-` (Ctrl), `M-` (Meta/Alt) and `S-` (Shift)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Key {
+    pub code: KeyCode,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+}
+
+impl Key {
+    /// The bytes the key sends to the program. `application_cursor` is the mode requested by the
+    /// program (DECCKM), in which the cursor keys send different sequences.
+    pub fn encode(&self, application_cursor: bool, out: &mut Vec<u8>) {
+        // The xterm modifier parameter, 1 means no modifiers
+        let modifier = 1 + u8::from(self.shift) + 2 * u8::from(self.alt) + 4 * u8::from(self.ctrl);
+        let cursor_key = |out: &mut Vec<u8>, final_byte: char| {
+            let sequence = match (modifier, application_cursor) {
+                (1, false) => format!("\x1b[{final_byte}"),
+                (1, true) => format!("\x1bO{final_byte}"),
+                _ => format!("\x1b[1;{modifier}{final_byte}"),
+            };
+            out.extend(sequence.as_bytes());
+        };
+        let tilde_key = |out: &mut Vec<u8>, number: u8| {
+            let sequence = match modifier {
+                1 => format!("\x1b[{number}~"),
+                _ => format!("\x1b[{number};{modifier}~"),
+            };
+            out.extend(sequence.as_bytes());
+        };
+        let alt_prefixed = |out: &mut Vec<u8>, bytes: &[u8]| {
+            if self.alt {
+                out.push(0x1b);
+            }
+            out.extend(bytes);
+        };
+
+        match self.code {
+            KeyCode::Char(ch) => {
+                let ch = if self.shift {
+                    ch.to_ascii_uppercase()
+                } else {
+                    ch
+                };
+                match ch {
+                    _ if self.ctrl => alt_prefixed(out, &[ctrl_byte(ch).unwrap_or(ch as u8)]),
+                    _ => alt_prefixed(out, ch.encode_utf8(&mut [0; 4]).as_bytes()),
+                }
+            }
+            KeyCode::Enter => alt_prefixed(out, b"\r"),
+            KeyCode::Tab if self.shift => out.extend(b"\x1b[Z"),
+            KeyCode::Tab => alt_prefixed(out, b"\t"),
+            KeyCode::Backspace => alt_prefixed(out, b"\x7f"),
+            KeyCode::Escape => alt_prefixed(out, b"\x1b"),
+            KeyCode::Space if self.ctrl => alt_prefixed(out, b"\0"),
+            KeyCode::Space => alt_prefixed(out, b" "),
+            KeyCode::Up => cursor_key(out, 'A'),
+            KeyCode::Down => cursor_key(out, 'B'),
+            KeyCode::Right => cursor_key(out, 'C'),
+            KeyCode::Left => cursor_key(out, 'D'),
+            KeyCode::Home => cursor_key(out, 'H'),
+            KeyCode::End => cursor_key(out, 'F'),
+            KeyCode::Insert => tilde_key(out, 2),
+            KeyCode::Delete => tilde_key(out, 3),
+            KeyCode::PageUp => tilde_key(out, 5),
+            KeyCode::PageDown => tilde_key(out, 6),
+            KeyCode::F(n @ 1..=4) if modifier == 1 => {
+                out.extend(b"\x1bO");
+                out.push(b'P' + n - 1);
+            }
+            KeyCode::F(n @ 1..=4) => {
+                out.extend(format!("\x1b[1;{modifier}{}", (b'P' + n - 1) as char).as_bytes())
+            }
+            KeyCode::F(n) => tilde_key(out, [15, 17, 18, 19, 20, 21, 23, 24][n as usize - 5]),
+        }
+    }
+
+    /// Checks the key can be sent, not every key can be combined with every modifier
+    fn validate(&self) -> anyhow::Result<()> {
+        let supported = match self.code {
+            KeyCode::Char(ch) => {
+                (!self.ctrl || ctrl_byte(ch.to_ascii_uppercase()).is_some())
+                    && (!self.shift || ch.is_ascii_alphabetic())
+            }
+            KeyCode::Enter | KeyCode::Backspace | KeyCode::Escape => !self.ctrl && !self.shift,
+            KeyCode::Tab => !(self.ctrl || self.alt && self.shift),
+            KeyCode::Space => !self.shift,
+            KeyCode::F(n) => (1..=12).contains(&n),
+            _ => true,
+        };
+        if !supported {
+            bail!("Unsupported key combination: {self}");
+        }
+        Ok(())
+    }
+}
+
+/// The control character sent with Ctrl, e.g. Ctrl+C is 0x03
+fn ctrl_byte(ch: char) -> Option<u8> {
+    match ch.to_ascii_uppercase() {
+        ch @ ('@'..='_') => Some(ch as u8 & 0x1f),
+        '?' => Some(0x7f),
+        _ => None,
+    }
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut key = Key {
+            code: KeyCode::Space,
+            ctrl: false,
+            alt: false,
+            shift: false,
+        };
+        let mut name = s;
+        // At least one character has to remain after the prefix, so `M--` is Alt and `-`
+        while name.len() > 2 {
+            // `get` as the first character isn't necessarily ASCII
+            match name.get(..2) {
+                Some("C-") => key.ctrl = true,
+                Some("M-") => key.alt = true,
+                Some("S-") => key.shift = true,
+                _ => break,
+            }
+            name = &name[2..];
+        }
+
+        let mut chars = name.chars();
+        key.code = match (chars.next(), chars.next()) {
+            (Some(ch), None) => KeyCode::Char(ch),
+            _ => {
+                if let Some((code, _)) = KEY_NAMES
+                    .iter()
+                    .find(|(_, names)| names.iter().any(|known| known.eq_ignore_ascii_case(name)))
+                {
+                    *code
+                } else if let Some(n) = name.strip_prefix(['F', 'f']) {
+                    KeyCode::F(n.parse().ok().context(format!("Unknown key: {s}"))?)
+                } else {
+                    bail!("Unknown key: {s}");
+                }
+            }
+        };
+        key.validate()?;
+        Ok(key)
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (set, prefix) in [(self.ctrl, "C-"), (self.alt, "M-"), (self.shift, "S-")] {
+            if set {
+                f.write_str(prefix)?;
+            }
+        }
+        match self.code {
+            KeyCode::F(n) => write!(f, "F{n}"),
+            code => match KEY_NAMES.iter().find(|(known, _)| *known == code) {
+                Some((_, names)) => f.write_str(names[0]),
+                None => match code {
+                    KeyCode::Char(ch) => write!(f, "{ch}"),
+                    _ => unreachable!("All other keys have a name"),
+                },
+            },
+        }
+    }
+}
+
+/// Parses keys separated by whitespace
+pub fn parse_keys(s: &str) -> anyhow::Result<Vec<Key>> {
+    s.split_whitespace().map(Key::from_str).collect()
+}
+
+/// The bytes sent by pressing the keys one after another
+pub fn encode_keys(keys: &[Key], application_cursor: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    for key in keys {
+        key.encode(application_cursor, &mut out);
+    }
+    out
+}
+
+/// The bytes sent by pasting the text. Newlines are sent as `\r` like the Enter key, the text is
+/// wrapped in `ESC [200~` and `ESC [201~` if the program requested bracketed paste.
+pub fn encode_paste(text: &[u8], bracketed: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len() + 12);
+    if bracketed {
+        out.extend(b"\x1b[200~");
+    }
+    let mut bytes = text.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'\r' if bytes.peek() == Some(&&b'\n') => (),
+            b'\n' => out.push(b'\r'),
+            _ => out.push(byte),
+        }
+    }
+    if bracketed {
+        out.extend(b"\x1b[201~");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::{encode_keys, encode_paste, parse_keys, Key};
+
+    fn encode(keys: &str, application_cursor: bool) -> Vec<u8> {
+        encode_keys(&parse_keys(keys).unwrap(), application_cursor)
+    }
+
+    #[test]
+    fn test_encode_keys() {
+        assert_eq!(encode("a B Enter", false), b"aB\r");
+        assert_eq!(
+            encode("C-c C-[ M-x S-a C-M-a", false),
+            b"\x03\x1b\x1bxA\x1b\x01"
+        );
+        assert_eq!(encode("Up Left", false), b"\x1b[A\x1b[D");
+        assert_eq!(encode("Up Left", true), b"\x1bOA\x1bOD");
+        assert_eq!(encode("C-Up S-End", true), b"\x1b[1;5A\x1b[1;2F");
+        assert_eq!(encode("PageDown M-Delete", false), b"\x1b[6~\x1b[3;3~");
+        assert_eq!(
+            encode("F1 S-F2 F5 F12", false),
+            b"\x1bOP\x1b[1;2Q\x1b[15~\x1b[24~"
+        );
+        assert_eq!(encode("Tab S-Tab Space C-Space", false), b"\t\x1b[Z \0");
+        assert_eq!(encode("Backslash C-_ M-- -", false), b"\\\x1f\x1b--");
+        assert_eq!(encode("ž", false), "ž".as_bytes());
+        assert_eq!(encode("€ M-世", false), "€\x1b世".as_bytes());
+    }
+
+    #[test]
+    fn test_encode_paste() {
+        assert_eq!(encode_paste(b"a\nb\r\nc\r", false), b"a\rb\rc\r");
+        assert_eq!(encode_paste(b"ok\n", true), b"\x1b[200~ok\r\x1b[201~");
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = parse_keys("enter pgdn C-M-S-Up F10 Backslash x").unwrap();
+        let names: Vec<String> = keys.iter().map(Key::to_string).collect();
+        assert_eq!(
+            names,
+            ["Enter", "PageDown", "C-M-S-Up", "F10", "Backslash", "x"]
+        );
+
+        assert!(parse_keys("Foo").is_err());
+        assert!(parse_keys("F13").is_err());
+        assert!(parse_keys("C-1").is_err());
+        assert!(parse_keys("S-Enter").is_err());
+    }
+}
@@ -1,17 +1,26 @@
 pub mod cmd;
 pub mod event;
 pub mod file_format;
+pub mod keys;
+pub mod mouse;
+pub mod screen;
+pub mod stats;
+pub mod terminal;
 pub mod unbuffered_stdout;
 pub mod utils;
 
 use crate::cmd::benchmark::BenchmarkCmd;
 use crate::cmd::controlled_play::ControlledPlayCmd;
+use crate::cmd::export::ExportCmd;
+use crate::cmd::extract_input::ExtractInputCmd;
+use crate::cmd::import::ImportCmd;
 use crate::cmd::measure_cmd::MeasureCmd;
 use crate::cmd::play::PlayCmd;
 use crate::cmd::record::RecordCmd;
 use crate::cmd::transform::TransformCmd;
 use clap::{Parser, Subcommand};
 use log::LevelFilter;
+use std::process::ExitCode;
 
 #[derive(Subcommand)]
 enum CliCommand {
@@ -21,6 +30,9 @@ enum CliCommand {
     Record(RecordCmd),
     Measure(MeasureCmd),
     Benchmark(BenchmarkCmd),
+    ExtractInput(ExtractInputCmd),
+    Export(ExportCmd),
+    Import(ImportCmd),
 }
 
 #[derive(Parser)]
@@ -29,18 +41,22 @@ struct Cli {
     command: CliCommand,
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<ExitCode> {
     let args = Cli::parse();
     env_logger::builder()
         .filter_level(LevelFilter::Warn)
         .parse_default_env()
         .init();
     match args.command {
-        CliCommand::Transform(cmd) => cmd.run(),
-        CliCommand::ControlledPlay(cmd) => cmd.run(),
-        CliCommand::Play(cmd) => cmd.run(),
-        CliCommand::Record(cmd) => cmd.run(),
-        CliCommand::Measure(cmd) => cmd.run(),
-        CliCommand::Benchmark(cmd) => cmd.run(),
+        CliCommand::Transform(cmd) => cmd.run()?,
+        CliCommand::ControlledPlay(cmd) => cmd.run()?,
+        CliCommand::Play(cmd) => cmd.run()?,
+        CliCommand::Record(cmd) => return cmd.run(),
+        CliCommand::Measure(cmd) => cmd.run()?,
+        CliCommand::Benchmark(cmd) => cmd.run()?,
+        CliCommand::ExtractInput(cmd) => cmd.run()?,
+        CliCommand::Export(cmd) => cmd.run()?,
+        CliCommand::Import(cmd) => cmd.run()?,
     }
+    Ok(ExitCode::SUCCESS)
 }
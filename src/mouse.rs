@@ -0,0 +1,185 @@
+//! Mouse events used by the `c:` input command (e.g. `click`, `drag-right`, `wheel-up`) and the
+//! byte sequences reporting them, the same as xterm.
+
+use anyhow::{bail, ensure};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+}
+
+impl MouseButton {
+    fn code(self) -> u32 {
+        match self {
+            MouseButton::Left => 0,
+            MouseButton::Middle => 1,
+            MouseButton::Right => 2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MouseAction {
+    Press(MouseButton),
+    Release(MouseButton),
+    /// A press followed by a release
+    Click(MouseButton),
+    /// Moving the mouse while the button is pressed
+    Drag(MouseButton),
+    /// Moving the mouse without any button pressed
+    Move,
+    WheelUp,
+    WheelDown,
+}
+
+/// A mouse event at a cell of the terminal, the rows and columns are counted from 0
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Mouse {
+    pub action: MouseAction,
+    pub col: u16,
+    pub row: u16,
+}
+
+impl Mouse {
+    /// The bytes reporting the event to the program, using the SGR encoding (requested by the
+    /// program) or the X10 one. Fails if the position is too large for the X10 encoding.
+    pub fn encode(&self, sgr: bool, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let (col, row) = (u32::from(self.col) + 1, u32::from(self.row) + 1);
+        let mut report = |code: u32, release: bool| -> anyhow::Result<()> {
+            if sgr {
+                let final_byte = if release { 'm' } else { 'M' };
+                write!(out, "\x1b[<{code};{col};{row}{final_byte}")?;
+            } else {
+                ensure!(
+                    col <= 223 && row <= 223,
+                    "Mouse position {col}x{row} is too large for the X10 encoding"
+                );
+                // The X10 encoding doesn't say which button was released
+                let code = if release { 3 } else { code };
+                out.extend([0x1b, b'[', b'M']);
+                out.extend([code, col, row].map(|value| (32 + value) as u8));
+            }
+            Ok(())
+        };
+
+        match self.action {
+            MouseAction::Press(button) => report(button.code(), false),
+            MouseAction::Release(button) => report(button.code(), true),
+            MouseAction::Click(button) => {
+                report(button.code(), false)?;
+                report(button.code(), true)
+            }
+            MouseAction::Drag(button) => report(32 + button.code(), false),
+            MouseAction::Move => report(32 + 3, false),
+            MouseAction::WheelUp => report(64, false),
+            MouseAction::WheelDown => report(65, false),
+        }
+    }
+}
+
+impl FromStr for MouseAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "move" => return Ok(MouseAction::Move),
+            "wheel-up" => return Ok(MouseAction::WheelUp),
+            "wheel-down" => return Ok(MouseAction::WheelDown),
+            _ => (),
+        }
+        // The button of the other actions is given by a suffix (e.g. `click-right`), the default
+        // is the left button
+        let (name, button) = s.split_once('-').unwrap_or((s, "left"));
+        let button = match button {
+            "left" => MouseButton::Left,
+            "middle" => MouseButton::Middle,
+            "right" => MouseButton::Right,
+            _ => bail!("Unknown mouse button: {button} (expected left, middle or right)"),
+        };
+        let action = match name {
+            "press" => MouseAction::Press,
+            "release" => MouseAction::Release,
+            "click" => MouseAction::Click,
+            "drag" => MouseAction::Drag,
+            _ => bail!(
+                "Unknown mouse action: {s} (expected press, release, click, drag, move, wheel-up \
+                or wheel-down)"
+            ),
+        };
+        Ok(action(button))
+    }
+}
+
+impl Display for MouseAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (name, button) = match *self {
+            MouseAction::Press(button) => ("press", button),
+            MouseAction::Release(button) => ("release", button),
+            MouseAction::Click(button) => ("click", button),
+            MouseAction::Drag(button) => ("drag", button),
+            MouseAction::Move => return f.write_str("move"),
+            MouseAction::WheelUp => return f.write_str("wheel-up"),
+            MouseAction::WheelDown => return f.write_str("wheel-down"),
+        };
+        match button {
+            MouseButton::Left => f.write_str(name),
+            MouseButton::Middle => write!(f, "{name}-middle"),
+            MouseButton::Right => write!(f, "{name}-right"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mouse::{Mouse, MouseAction, MouseButton};
+
+    fn encode(action: &str, col: u16, row: u16, sgr: bool) -> anyhow::Result<Vec<u8>> {
+        let mouse = Mouse {
+            action: action.parse()?,
+            col,
+            row,
+        };
+        let mut out = Vec::new();
+        mouse.encode(sgr, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_encode_mouse() {
+        assert_eq!(
+            encode("click", 0, 4, true).unwrap(),
+            b"\x1b[<0;1;5M\x1b[<0;1;5m"
+        );
+        assert_eq!(encode("drag-right", 9, 2, true).unwrap(), b"\x1b[<34;10;3M");
+        assert_eq!(
+            encode("wheel-down", 300, 2, true).unwrap(),
+            b"\x1b[<65;301;3M"
+        );
+        assert_eq!(
+            encode("click-middle", 0, 4, false).unwrap(),
+            b"\x1b[M!!%\x1b[M#!%"
+        );
+        assert_eq!(encode("move", 1, 1, false).unwrap(), b"\x1b[MC\"\"");
+        assert_eq!(encode("wheel-up", 222, 0, false).unwrap(), b"\x1b[M`\xff!");
+        assert!(encode("press", 223, 0, false).is_err());
+    }
+
+    #[test]
+    fn test_parse_mouse_action() {
+        assert_eq!(
+            "release-right".parse::<MouseAction>().unwrap(),
+            MouseAction::Release(MouseButton::Right)
+        );
+        for name in ["click", "press-middle", "drag-right", "move", "wheel-up"] {
+            assert_eq!(name.parse::<MouseAction>().unwrap().to_string(), name);
+        }
+        assert!("click-top".parse::<MouseAction>().is_err());
+        assert!("tap".parse::<MouseAction>().is_err());
+        assert!("move-left".parse::<MouseAction>().is_err());
+    }
+}
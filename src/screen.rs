@@ -0,0 +1,1144 @@
+//! In-process terminal emulator, used to render the output of a recording into frames.
+//!
+//! Implements the subset of VT100/xterm behaviour that is commonly used by full-screen terminal
+//! applications: cursor movement, erasing, scrolling regions, insert/delete, SGR attributes
+//! (including 256 and true colors), the alternate screen, line drawing characters and wide
+//! characters. Sequences that are not understood are ignored.
+
+use crate::file_format::TerminalSize;
+use std::fmt::Write;
+use unicode_width::UnicodeWidthChar;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Color {
+    #[default]
+    Default,
+    /// One of the 16 basic colors (SGR 30-37, 90-97 and 40-47, 100-107)
+    Ansi(u8),
+    /// A color from the 256 color palette (SGR 38;5 and 48;5)
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    fn sgr_codes(&self, base: u16) -> Vec<u16> {
+        match *self {
+            Color::Default => vec![base + 9],
+            Color::Ansi(n @ 0..=7) => vec![base + n as u16],
+            Color::Ansi(n) => vec![base + 60 + n as u16 - 8],
+            Color::Indexed(n) => vec![base + 8, 5, n as u16],
+            Color::Rgb(r, g, b) => vec![base + 8, 2, r as u16, g as u16, b as u16],
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Attributes {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub reverse: bool,
+    pub hidden: bool,
+    pub strikethrough: bool,
+}
+
+impl Attributes {
+    fn flags(&self) -> [(bool, u16); 8] {
+        [
+            (self.bold, 1),
+            (self.dim, 2),
+            (self.italic, 3),
+            (self.underline, 4),
+            (self.blink, 5),
+            (self.reverse, 7),
+            (self.hidden, 8),
+            (self.strikethrough, 9),
+        ]
+    }
+
+    /// Writes the SGR escape sequences changing the attributes from `last` to `self`.
+    /// The sequences are the same as the ones produced by `tmux capture-pane -e`.
+    fn write_sgr_change(&self, last: &Attributes, out: &mut String) {
+        let mut codes = Vec::new();
+        let mut last_flags = last.flags();
+        // If any attribute is removed, start from a reset
+        if last_flags
+            .iter()
+            .zip(self.flags())
+            .any(|((was_set, _), (set, _))| *was_set && !set)
+        {
+            codes.push(0);
+            last_flags = Attributes::default().flags();
+        }
+        for ((was_set, _), (set, code)) in last_flags.iter().zip(self.flags()) {
+            if set && !was_set {
+                codes.push(code);
+            }
+        }
+        let reset = codes.first() == Some(&0);
+        write_sgr(out, &codes);
+
+        for (new, old, base) in [(self.fg, last.fg, 30), (self.bg, last.bg, 40)] {
+            if new != old || reset {
+                write_sgr(out, &new.sgr_codes(base));
+            }
+        }
+    }
+}
+
+fn write_sgr(out: &mut String, codes: &[u16]) {
+    if let Some((first, rest)) = codes.split_first() {
+        write!(out, "\x1b[{first}").unwrap();
+        for code in rest {
+            write!(out, ";{code}").unwrap();
+        }
+        out.push('m');
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Cell {
+    /// The character in the cell, `None` if the cell is covered by a wide character on its left
+    ch: Option<char>,
+    attrs: Attributes,
+}
+
+impl Cell {
+    fn blank(attrs: Attributes) -> Self {
+        // Erased cells keep only the background color (background color erase)
+        Self {
+            ch: Some(' '),
+            attrs: Attributes {
+                bg: attrs.bg,
+                ..Attributes::default()
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Line {
+    cells: Vec<Cell>,
+    /// The line was wrapped, its text continues on the next line
+    wrapped: bool,
+}
+
+impl Line {
+    fn blank(cols: usize, attrs: Attributes) -> Self {
+        Self {
+            cells: vec![Cell::blank(attrs); cols],
+            wrapped: false,
+        }
+    }
+}
+
+type Grid = Vec<Line>;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+enum Charset {
+    #[default]
+    Ascii,
+    LineDrawing,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Cursor {
+    row: usize,
+    col: usize,
+    attrs: Attributes,
+    /// The cursor is past the last column, the next printed character wraps to the next line
+    pending_wrap: bool,
+    origin_mode: bool,
+    charsets: [Charset; 2],
+    active_charset: usize,
+}
+
+enum State {
+    Ground,
+    Escape,
+    /// Escape sequence with intermediate bytes, only designating charsets is supported
+    EscapeIntermediate(u8),
+    Csi,
+    /// OSC, DCS, SOS, PM and APC strings, which are ignored. The flag signals an ESC was seen.
+    String(bool),
+}
+
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    grid: Grid,
+    /// The primary screen while the alternate screen is active
+    primary_grid: Option<Grid>,
+    cursor: Cursor,
+    saved_cursor: Option<Cursor>,
+    scroll_top: usize,
+    scroll_bottom: usize,
+    tab_stops: Vec<bool>,
+    autowrap: bool,
+    /// Cursor keys send application sequences (DECCKM)
+    application_cursor: bool,
+    /// Pasted text is wrapped in `ESC [200~` and `ESC [201~`
+    bracketed_paste: bool,
+    /// Mouse events are reported (X10, normal, button or any event tracking)
+    mouse_tracking: bool,
+    /// Mouse events are reported using the SGR encoding instead of the X10 one
+    sgr_mouse: bool,
+    insert_mode: bool,
+    last_printed: Option<char>,
+
+    state: State,
+    params: Vec<u16>,
+    intermediates: Vec<u8>,
+    private_marker: Option<u8>,
+    utf8_buf: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(size: TerminalSize) -> Self {
+        let cols = (size.cols as usize).max(1);
+        let rows = (size.rows as usize).max(1);
+        Self {
+            cols,
+            rows,
+            grid: blank_grid(cols, rows, Attributes::default()),
+            primary_grid: None,
+            cursor: Cursor::default(),
+            saved_cursor: None,
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            tab_stops: default_tab_stops(cols),
+            autowrap: true,
+            application_cursor: false,
+            bracketed_paste: false,
+            mouse_tracking: false,
+            sgr_mouse: false,
+            insert_mode: false,
+            last_printed: None,
+            state: State::Ground,
+            params: Vec::new(),
+            intermediates: Vec::new(),
+            private_marker: None,
+            utf8_buf: Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> TerminalSize {
+        TerminalSize {
+            cols: self.cols as u16,
+            rows: self.rows as u16,
+            xpixel: 0,
+            ypixel: 0,
+        }
+    }
+
+    pub fn resize(&mut self, size: TerminalSize) {
+        let cols = (size.cols as usize).max(1);
+        let rows = (size.rows as usize).max(1);
+
+        // Drop lines from the top when shrinking, so the cursor line stays visible
+        let scrolled = (self.cursor.row + 1).saturating_sub(rows);
+        resize_grid(&mut self.grid, cols, rows, scrolled);
+        if let Some(primary_grid) = &mut self.primary_grid {
+            resize_grid(primary_grid, cols, rows, 0);
+        }
+
+        self.cols = cols;
+        self.rows = rows;
+        self.cursor.row -= scrolled;
+        self.cursor.col = self.cursor.col.min(cols - 1);
+        self.cursor.pending_wrap = false;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.tab_stops = default_tab_stops(cols);
+    }
+
+    pub fn process(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.process_byte(byte);
+        }
+    }
+
+    /// Renders the visible screen as text, one line per row with trailing blanks removed. Wrapped
+    /// lines are joined together. With `escapes` the text attributes are represented using SGR
+    /// escape sequences.
+    pub fn render(&self, escapes: bool) -> String {
+        let mut out = String::with_capacity(self.cols * self.rows);
+        let mut attrs = Attributes::default();
+        for (row, line) in self.grid.iter().enumerate() {
+            let joined = line.wrapped && row + 1 < self.rows;
+            let end = if joined {
+                self.cols
+            } else {
+                line.cells
+                    .iter()
+                    .rposition(|cell| {
+                        cell.ch != Some(' ') || (escapes && cell.attrs != Attributes::default())
+                    })
+                    .map_or(0, |i| i + 1)
+            };
+
+            for cell in &line.cells[..end] {
+                let Some(ch) = cell.ch else { continue };
+                if escapes && cell.attrs != attrs {
+                    cell.attrs.write_sgr_change(&attrs, &mut out);
+                    attrs = cell.attrs;
+                }
+                out.push(ch);
+            }
+            if !joined {
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Whether the application requested the cursor keys to send application sequences
+    pub fn application_cursor(&self) -> bool {
+        self.application_cursor
+    }
+
+    /// Whether the application requested pasted text to be bracketed
+    pub fn bracketed_paste(&self) -> bool {
+        self.bracketed_paste
+    }
+
+    /// Whether the application requested mouse events to be reported
+    pub fn mouse_tracking(&self) -> bool {
+        self.mouse_tracking
+    }
+
+    /// Whether the application requested the SGR encoding of mouse events
+    pub fn sgr_mouse(&self) -> bool {
+        self.sgr_mouse
+    }
+
+    /// Returns the text of a rectangular region of the screen (without attributes), rows are
+    /// separated by newlines. The region is inclusive and clamped to the screen size.
+    pub fn region_text(&self, top: usize, left: usize, bottom: usize, right: usize) -> String {
+        let right = right.min(self.cols - 1);
+        let mut out = String::new();
+        for line in self.grid.iter().take(bottom + 1).skip(top) {
+            if left <= right {
+                out.extend(line.cells[left..=right].iter().filter_map(|cell| cell.ch));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn process_byte(&mut self, byte: u8) {
+        match self.state {
+            State::Ground => self.ground(byte),
+            State::Escape => self.escape(byte),
+            State::EscapeIntermediate(intermediate) => {
+                if byte.is_ascii_control() {
+                    self.control(byte);
+                } else {
+                    self.designate_charset(intermediate, byte);
+                    self.state = State::Ground;
+                }
+            }
+            State::Csi => self.csi(byte),
+            State::String(esc_seen) => match byte {
+                0x07 => self.state = State::Ground,
+                b'\\' if esc_seen => self.state = State::Ground,
+                0x1b => self.state = State::String(true),
+                _ if esc_seen => self.escape(byte),
+                _ => self.state = State::String(false),
+            },
+        }
+    }
+
+    fn ground(&mut self, byte: u8) {
+        if !self.utf8_buf.is_empty() {
+            if byte & 0xc0 == 0x80 {
+                self.utf8_buf.push(byte);
+                if self.utf8_buf.len() == utf8_len(self.utf8_buf[0]) {
+                    let ch = std::str::from_utf8(&self.utf8_buf)
+                        .ok()
+                        .and_then(|s| s.chars().next())
+                        .unwrap_or(char::REPLACEMENT_CHARACTER);
+                    self.utf8_buf.clear();
+                    self.print(ch);
+                }
+                return;
+            }
+            // Invalid sequence, continue processing the byte as usual
+            self.utf8_buf.clear();
+            self.print(char::REPLACEMENT_CHARACTER);
+        }
+
+        match byte {
+            0x00..=0x1f => self.control(byte),
+            0x20..=0x7e => self.print(byte as char),
+            0x7f => (),
+            _ if utf8_len(byte) > 1 => self.utf8_buf.push(byte),
+            _ => self.print(char::REPLACEMENT_CHARACTER),
+        }
+    }
+
+    fn control(&mut self, byte: u8) {
+        match byte {
+            0x08 => {
+                self.cursor.col = self.cursor.col.saturating_sub(1);
+                self.cursor.pending_wrap = false;
+            }
+            0x09 => self.tab_forward(1),
+            0x0a..=0x0c => self.linefeed(),
+            0x0d => {
+                self.cursor.col = 0;
+                self.cursor.pending_wrap = false;
+            }
+            0x0e => self.cursor.active_charset = 1,
+            0x0f => self.cursor.active_charset = 0,
+            0x18 | 0x1a => self.state = State::Ground,
+            0x1b => {
+                self.intermediates.clear();
+                self.state = State::Escape;
+            }
+            _ => (),
+        }
+    }
+
+    fn escape(&mut self, byte: u8) {
+        self.state = State::Ground;
+        match byte {
+            b'[' => {
+                self.params.clear();
+                self.intermediates.clear();
+                self.private_marker = None;
+                self.state = State::Csi;
+            }
+            b']' | b'P' | b'X' | b'^' | b'_' => self.state = State::String(false),
+            b'(' | b')' | b'*' | b'+' | b'#' | b'%' => self.state = State::EscapeIntermediate(byte),
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            b'D' => self.linefeed(),
+            b'E' => {
+                self.cursor.col = 0;
+                self.linefeed();
+            }
+            b'H' => self.tab_stops[self.cursor.col] = true,
+            b'M' => self.reverse_index(),
+            b'c' => *self = Screen::new(self.size()),
+            0x00..=0x1f => self.control(byte),
+            _ => (),
+        }
+    }
+
+    fn designate_charset(&mut self, intermediate: u8, byte: u8) {
+        let charset = if byte == b'0' {
+            Charset::LineDrawing
+        } else {
+            Charset::Ascii
+        };
+        match intermediate {
+            b'(' => self.cursor.charsets[0] = charset,
+            b')' => self.cursor.charsets[1] = charset,
+            b'#' if byte == b'8' => {
+                // DECALN: fill the screen with 'E'
+                for cell in self.grid.iter_mut().flat_map(|line| &mut line.cells) {
+                    *cell = Cell {
+                        ch: Some('E'),
+                        attrs: Attributes::default(),
+                    };
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn csi(&mut self, byte: u8) {
+        match byte {
+            b'0'..=b'9' => {
+                if self.params.is_empty() {
+                    self.params.push(0);
+                }
+                let param = self.params.last_mut().unwrap();
+                *param = param
+                    .saturating_mul(10)
+                    .saturating_add((byte - b'0') as u16);
+            }
+            b';' | b':' => {
+                if self.params.is_empty() {
+                    self.params.push(0);
+                }
+                self.params.push(0);
+            }
+            b'<'..=b'?' => self.private_marker = Some(byte),
+            0x20..=0x2f => self.intermediates.push(byte),
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                self.csi_dispatch(byte);
+            }
+            _ => self.control(byte),
+        }
+    }
+
+    /// Returns the n-th parameter, or `default` if it is missing or 0
+    fn param(&self, n: usize, default: u16) -> usize {
+        match self.params.get(n) {
+            Some(0) | None => default as usize,
+            Some(&p) => p as usize,
+        }
+    }
+
+    fn csi_dispatch(&mut self, byte: u8) {
+        if !self.intermediates.is_empty() {
+            return;
+        }
+        if self.private_marker == Some(b'?') {
+            match byte {
+                b'h' => self.set_private_modes(true),
+                b'l' => self.set_private_modes(false),
+                _ => (),
+            }
+            return;
+        } else if self.private_marker.is_some() {
+            return;
+        }
+
+        let n = self.param(0, 1);
+        match byte {
+            b'@' => self.insert_blanks(n),
+            b'A' => self.move_cursor_up(n),
+            b'B' | b'e' => self.move_cursor_down(n),
+            b'C' | b'a' => self.set_cursor_col(self.cursor.col.saturating_add(n)),
+            b'D' => self.set_cursor_col(self.cursor.col.saturating_sub(n)),
+            b'E' => {
+                self.move_cursor_down(n);
+                self.cursor.col = 0;
+            }
+            b'F' => {
+                self.move_cursor_up(n);
+                self.cursor.col = 0;
+            }
+            b'G' | b'`' => self.set_cursor_col(n - 1),
+            b'H' | b'f' => self.set_cursor_position(n - 1, self.param(1, 1) - 1),
+            b'I' => self.tab_forward(n),
+            b'J' => self.erase_in_display(self.param(0, 0)),
+            b'K' => self.erase_in_line(self.param(0, 0)),
+            b'L' => self.insert_lines(n),
+            b'M' => self.delete_lines(n),
+            b'P' => self.delete_chars(n),
+            b'S' => self.scroll_up(n),
+            b'T' => self.scroll_down(n),
+            b'X' => self.erase_chars(n),
+            b'Z' => self.tab_backward(n),
+            b'b' => {
+                if let Some(ch) = self.last_printed {
+                    for _ in 0..n.min(self.cols * self.rows) {
+                        self.print(ch);
+                    }
+                }
+            }
+            b'd' => {
+                let row = self.origin_row(n - 1);
+                self.cursor.row = row;
+                self.cursor.pending_wrap = false;
+            }
+            b'g' => match self.param(0, 0) {
+                0 => self.tab_stops[self.cursor.col] = false,
+                3 => self.tab_stops.fill(false),
+                _ => (),
+            },
+            b'h' | b'l' if self.params.contains(&4) => self.insert_mode = byte == b'h',
+            b'm' => self.set_graphics_rendition(),
+            b'r' => {
+                let top = self.param(0, 1) - 1;
+                let bottom = self.param(1, self.rows as u16) - 1;
+                if top < bottom && bottom < self.rows {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.set_cursor_position(0, 0);
+                }
+            }
+            b's' => self.save_cursor(),
+            b'u' => self.restore_cursor(),
+            _ => (),
+        }
+    }
+
+    fn set_private_modes(&mut self, enable: bool) {
+        for i in 0..self.params.len() {
+            match self.params[i] {
+                1 => self.application_cursor = enable,
+                9 | 1000 | 1002 | 1003 => self.mouse_tracking = enable,
+                1006 => self.sgr_mouse = enable,
+                2004 => self.bracketed_paste = enable,
+                6 => {
+                    self.cursor.origin_mode = enable;
+                    self.set_cursor_position(0, 0);
+                }
+                7 => self.autowrap = enable,
+                47 | 1047 => self.set_alternate_screen(enable),
+                1048 if enable => self.save_cursor(),
+                1048 => self.restore_cursor(),
+                1049 => {
+                    if enable {
+                        self.save_cursor();
+                        self.set_alternate_screen(true);
+                        self.erase_in_display(2);
+                    } else {
+                        self.set_alternate_screen(false);
+                        self.restore_cursor();
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn set_alternate_screen(&mut self, enable: bool) {
+        if enable && self.primary_grid.is_none() {
+            let alternate = blank_grid(self.cols, self.rows, Attributes::default());
+            self.primary_grid = Some(std::mem::replace(&mut self.grid, alternate));
+        } else if !enable {
+            if let Some(primary_grid) = self.primary_grid.take() {
+                self.grid = primary_grid;
+            }
+        }
+    }
+
+    fn set_graphics_rendition(&mut self) {
+        if self.params.is_empty() {
+            self.cursor.attrs = Attributes::default();
+            return;
+        }
+
+        let attrs = &mut self.cursor.attrs;
+        let mut params = self.params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => *attrs = Attributes::default(),
+                1 => attrs.bold = true,
+                2 => attrs.dim = true,
+                3 => attrs.italic = true,
+                4 | 21 => attrs.underline = true,
+                5 | 6 => attrs.blink = true,
+                7 => attrs.reverse = true,
+                8 => attrs.hidden = true,
+                9 => attrs.strikethrough = true,
+                22 => {
+                    attrs.bold = false;
+                    attrs.dim = false;
+                }
+                23 => attrs.italic = false,
+                24 => attrs.underline = false,
+                25 => attrs.blink = false,
+                27 => attrs.reverse = false,
+                28 => attrs.hidden = false,
+                29 => attrs.strikethrough = false,
+                30..=37 => attrs.fg = Color::Ansi((param - 30) as u8),
+                38 => attrs.fg = parse_extended_color(&mut params).unwrap_or(attrs.fg),
+                39 => attrs.fg = Color::Default,
+                40..=47 => attrs.bg = Color::Ansi((param - 40) as u8),
+                48 => attrs.bg = parse_extended_color(&mut params).unwrap_or(attrs.bg),
+                49 => attrs.bg = Color::Default,
+                90..=97 => attrs.fg = Color::Ansi((param - 90 + 8) as u8),
+                100..=107 => attrs.bg = Color::Ansi((param - 100 + 8) as u8),
+                _ => (),
+            }
+        }
+    }
+
+    fn print(&mut self, ch: char) {
+        let ch = match self.cursor.charsets[self.cursor.active_charset] {
+            Charset::LineDrawing => line_drawing_char(ch),
+            Charset::Ascii => ch,
+        };
+        let width = match ch.width() {
+            // Combining characters are not supported, they are dropped
+            Some(0) | None => return,
+            Some(width) => width.min(2),
+        };
+        if width > self.cols {
+            return;
+        }
+        self.last_printed = Some(ch);
+
+        if self.cursor.pending_wrap || (width == 2 && self.cursor.col + 1 >= self.cols) {
+            if self.autowrap {
+                if width == 2 && !self.cursor.pending_wrap {
+                    self.clear_cell(self.cursor.row, self.cursor.col);
+                }
+                self.grid[self.cursor.row].wrapped = true;
+                self.cursor.col = 0;
+                self.linefeed();
+            } else if width == 2 && self.cols < 2 {
+                return;
+            } else if width == 2 {
+                self.cursor.col = self.cols - 2;
+            }
+            self.cursor.pending_wrap = false;
+        }
+
+        if self.insert_mode {
+            self.insert_blanks(width);
+        }
+
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        self.clear_cell(row, col);
+        self.grid[row].cells[col] = Cell {
+            ch: Some(ch),
+            attrs: self.cursor.attrs,
+        };
+        if width == 2 {
+            self.clear_cell(row, col + 1);
+            self.grid[row].cells[col + 1] = Cell {
+                ch: None,
+                attrs: self.cursor.attrs,
+            };
+        }
+
+        if col + width >= self.cols {
+            self.cursor.col = self.cols - 1;
+            self.cursor.pending_wrap = self.autowrap;
+        } else {
+            self.cursor.col = col + width;
+        }
+    }
+
+    /// Blanks a cell, including the other half of a wide character it is part of
+    fn clear_cell(&mut self, row: usize, col: usize) {
+        let line = &mut self.grid[row].cells;
+        if line[col].ch.is_none() && col > 0 {
+            line[col - 1] = Cell::blank(line[col - 1].attrs);
+        }
+        if line.get(col + 1).is_some_and(|cell| cell.ch.is_none()) {
+            line[col + 1] = Cell::blank(line[col + 1].attrs);
+        }
+        line[col] = Cell::blank(line[col].attrs);
+    }
+
+    fn blank(&self) -> Cell {
+        Cell::blank(self.cursor.attrs)
+    }
+
+    fn linefeed(&mut self) {
+        self.cursor.pending_wrap = false;
+        if self.cursor.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor.row + 1 < self.rows {
+            self.cursor.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.cursor.pending_wrap = false;
+        if self.cursor.row == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.cursor.row > 0 {
+            self.cursor.row -= 1;
+        }
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        let region_height = self.scroll_bottom - self.scroll_top + 1;
+        let n = n.min(region_height);
+        let blank_line = Line::blank(self.cols, self.cursor.attrs);
+        self.grid.drain(self.scroll_top..self.scroll_top + n);
+        let at = self.scroll_bottom + 1 - n;
+        self.grid.splice(at..at, std::iter::repeat_n(blank_line, n));
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        let region_height = self.scroll_bottom - self.scroll_top + 1;
+        let n = n.min(region_height);
+        let blank_line = Line::blank(self.cols, self.cursor.attrs);
+        self.grid
+            .drain(self.scroll_bottom + 1 - n..=self.scroll_bottom);
+        self.grid.splice(
+            self.scroll_top..self.scroll_top,
+            std::iter::repeat_n(blank_line, n),
+        );
+    }
+
+    fn insert_lines(&mut self, n: usize) {
+        if (self.scroll_top..=self.scroll_bottom).contains(&self.cursor.row) {
+            let top = std::mem::replace(&mut self.scroll_top, self.cursor.row);
+            self.scroll_down(n);
+            self.scroll_top = top;
+            self.cursor.col = 0;
+            self.cursor.pending_wrap = false;
+        }
+    }
+
+    fn delete_lines(&mut self, n: usize) {
+        if (self.scroll_top..=self.scroll_bottom).contains(&self.cursor.row) {
+            let top = std::mem::replace(&mut self.scroll_top, self.cursor.row);
+            self.scroll_up(n);
+            self.scroll_top = top;
+            self.cursor.col = 0;
+            self.cursor.pending_wrap = false;
+        }
+    }
+
+    fn insert_blanks(&mut self, n: usize) {
+        let blank = self.blank();
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        let n = n.min(self.cols - col);
+        let line = &mut self.grid[row].cells;
+        // Inserting in the middle of a wide character splits it, the character at the cursor is
+        // shifted right otherwise
+        if line[col].ch.is_none() && col > 0 {
+            line[col - 1] = Cell::blank(line[col - 1].attrs);
+            line[col] = Cell::blank(line[col].attrs);
+        }
+        line.truncate(self.cols - n);
+        line.splice(col..col, std::iter::repeat_n(blank, n));
+        if is_wide(&line[self.cols - 1]) {
+            line[self.cols - 1] = blank;
+        }
+        self.cursor.pending_wrap = false;
+    }
+
+    fn delete_chars(&mut self, n: usize) {
+        let blank = self.blank();
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        let n = n.min(self.cols - col);
+        self.clear_cell(row, col);
+        if self.grid[row]
+            .cells
+            .get(col + n)
+            .is_some_and(|cell| cell.ch.is_none())
+        {
+            self.clear_cell(row, col + n);
+        }
+        let line = &mut self.grid[row].cells;
+        line.drain(col..col + n);
+        line.extend(std::iter::repeat_n(blank, n));
+        self.cursor.pending_wrap = false;
+    }
+
+    fn erase_chars(&mut self, n: usize) {
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        let end = col.saturating_add(n).min(self.cols);
+        self.erase_cells(row, col, end);
+        self.cursor.pending_wrap = false;
+    }
+
+    /// Erases cells in the range `start..end` of a row
+    fn erase_cells(&mut self, row: usize, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        let blank = self.blank();
+        self.clear_cell(row, start);
+        self.clear_cell(row, end - 1);
+        let line = &mut self.grid[row];
+        line.cells[start..end].fill(blank);
+        if end == self.cols {
+            line.wrapped = false;
+        }
+    }
+
+    fn erase_in_line(&mut self, mode: usize) {
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        match mode {
+            0 => self.erase_cells(row, col, self.cols),
+            1 => self.erase_cells(row, 0, col + 1),
+            2 => self.erase_cells(row, 0, self.cols),
+            _ => (),
+        }
+    }
+
+    fn erase_in_display(&mut self, mode: usize) {
+        let row = self.cursor.row;
+        let rows = match mode {
+            0 => {
+                self.erase_in_line(0);
+                row + 1..self.rows
+            }
+            1 => {
+                self.erase_in_line(1);
+                0..row
+            }
+            2 => 0..self.rows,
+            _ => return,
+        };
+        for row in rows {
+            self.erase_cells(row, 0, self.cols);
+        }
+    }
+
+    fn tab_forward(&mut self, n: usize) {
+        for _ in 0..n {
+            let next = (self.cursor.col + 1..self.cols).find(|&col| self.tab_stops[col]);
+            self.cursor.col = next.unwrap_or(self.cols - 1);
+        }
+        self.cursor.pending_wrap = false;
+    }
+
+    fn tab_backward(&mut self, n: usize) {
+        for _ in 0..n {
+            let prev = (0..self.cursor.col).rev().find(|&col| self.tab_stops[col]);
+            self.cursor.col = prev.unwrap_or(0);
+        }
+        self.cursor.pending_wrap = false;
+    }
+
+    /// Converts a row relative to the origin (scroll region in origin mode) to an absolute row
+    fn origin_row(&self, row: usize) -> usize {
+        if self.cursor.origin_mode {
+            (self.scroll_top + row).min(self.scroll_bottom)
+        } else {
+            row.min(self.rows - 1)
+        }
+    }
+
+    fn set_cursor_position(&mut self, row: usize, col: usize) {
+        self.cursor.row = self.origin_row(row);
+        self.set_cursor_col(col);
+    }
+
+    fn set_cursor_col(&mut self, col: usize) {
+        self.cursor.col = col.min(self.cols - 1);
+        self.cursor.pending_wrap = false;
+    }
+
+    fn move_cursor_up(&mut self, n: usize) {
+        let limit = if self.cursor.row >= self.scroll_top {
+            self.scroll_top
+        } else {
+            0
+        };
+        self.cursor.row = self.cursor.row.saturating_sub(n).max(limit);
+        self.cursor.pending_wrap = false;
+    }
+
+    fn move_cursor_down(&mut self, n: usize) {
+        let limit = if self.cursor.row <= self.scroll_bottom {
+            self.scroll_bottom
+        } else {
+            self.rows - 1
+        };
+        self.cursor.row = self.cursor.row.saturating_add(n).min(limit);
+        self.cursor.pending_wrap = false;
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = Some(self.cursor.clone());
+    }
+
+    fn restore_cursor(&mut self) {
+        let mut cursor = self.saved_cursor.clone().unwrap_or_default();
+        cursor.row = cursor.row.min(self.rows - 1);
+        cursor.col = cursor.col.min(self.cols - 1);
+        self.cursor = cursor;
+    }
+}
+
+fn blank_grid(cols: usize, rows: usize, attrs: Attributes) -> Grid {
+    vec![Line::blank(cols, attrs); rows]
+}
+
+fn resize_grid(grid: &mut Grid, cols: usize, rows: usize, scrolled: usize) {
+    grid.drain(..scrolled);
+    grid.resize(rows, Line::blank(cols, Attributes::default()));
+    for line in grid.iter_mut() {
+        line.cells.resize(cols, Cell::blank(Attributes::default()));
+        if is_wide(&line.cells[cols - 1]) {
+            line.cells[cols - 1] = Cell::blank(Attributes::default());
+        }
+    }
+}
+
+/// Whether the cell contains the first half of a wide character
+fn is_wide(cell: &Cell) -> bool {
+    cell.ch.and_then(|ch| ch.width()) == Some(2)
+}
+
+fn default_tab_stops(cols: usize) -> Vec<bool> {
+    (0..cols).map(|col| col % 8 == 0 && col != 0).collect()
+}
+
+fn utf8_len(first_byte: u8) -> usize {
+    match first_byte {
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => 1,
+    }
+}
+
+fn parse_extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match params.next()? {
+        5 => Some(Color::Indexed(params.next()?.min(255) as u8)),
+        2 => {
+            let mut component = || params.next().map(|c| c.min(255) as u8);
+            Some(Color::Rgb(component()?, component()?, component()?))
+        }
+        _ => None,
+    }
+}
+
+/// Maps a character to the DEC special graphics (line drawing) character set
+fn line_drawing_char(ch: char) -> char {
+    match ch {
+        '`' => '◆',
+        'a' => '▒',
+        'b' => '␉',
+        'c' => '␌',
+        'd' => '␍',
+        'e' => '␊',
+        'f' => '°',
+        'g' => '±',
+        'h' => '␤',
+        'i' => '␋',
+        'j' => '┘',
+        'k' => '┐',
+        'l' => '┌',
+        'm' => '└',
+        'n' => '┼',
+        'o' => '⎺',
+        'p' => '⎻',
+        'q' => '─',
+        'r' => '⎼',
+        's' => '⎽',
+        't' => '├',
+        'u' => '┤',
+        'v' => '┴',
+        'w' => '┬',
+        'x' => '│',
+        'y' => '≤',
+        'z' => '≥',
+        '{' => 'π',
+        '|' => '≠',
+        '}' => '£',
+        '~' => '·',
+        _ => ch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_format::TerminalSize;
+    use crate::screen::Screen;
+
+    fn screen(cols: u16, rows: u16) -> Screen {
+        Screen::new(TerminalSize {
+            cols,
+            rows,
+            xpixel: 0,
+            ypixel: 0,
+        })
+    }
+
+    #[test]
+    fn test_print_and_wrap() {
+        let mut s = screen(5, 3);
+        s.process(b"hello world");
+        assert_eq!(s.render(false), "hello world\n");
+        s.process(b"\r\nx");
+        assert_eq!(s.render(false), " world\nx\n");
+    }
+
+    #[test]
+    fn test_cursor_movement_and_erase() {
+        let mut s = screen(10, 3);
+        s.process(b"aaaaaaaaaa\r\nbbbbbbbbbb\r\ncccccccccc");
+        s.process(b"\x1b[2;3H\x1b[K\x1b[1;5H\x1b[1K\x1b[3;2H\x1b[2X");
+        assert_eq!(s.render(false), "     aaaaa\nbb\nc  ccccccc\n");
+        s.process(b"\x1b[2J\x1b[Hx");
+        assert_eq!(s.render(false), "x\n\n\n");
+    }
+
+    #[test]
+    fn test_region_text() {
+        let mut s = screen(6, 3);
+        s.process("abcdef\r\n\x1b[2Cgh\u{4e16}\r\nxyz".as_bytes());
+        assert_eq!(s.region_text(0, 1, 1, 4), "bcde\n gh\u{4e16}\n");
+        assert_eq!(s.region_text(2, 4, 10, 10), "  \n");
+        assert_eq!(s.region_text(1, 5, 0, 10), "");
+    }
+
+    #[test]
+    fn test_application_cursor() {
+        let mut s = screen(6, 3);
+        assert!(!s.application_cursor());
+        s.process(b"\x1b[?1h");
+        assert!(s.application_cursor());
+        s.process(b"\x1b[?1l");
+        assert!(!s.application_cursor());
+        s.process(b"\x1b[?1h\x1bc");
+        assert!(!s.application_cursor());
+    }
+
+    #[test]
+    fn test_paste_and_mouse_modes() {
+        let mut s = screen(6, 3);
+        s.process(b"\x1b[?2004;1002;1006h");
+        assert!(s.bracketed_paste() && s.mouse_tracking() && s.sgr_mouse());
+        s.process(b"\x1b[?1002l");
+        assert!(!s.mouse_tracking() && s.sgr_mouse());
+        s.process(b"\x1bc");
+        assert!(!s.bracketed_paste() && !s.sgr_mouse());
+    }
+
+    #[test]
+    fn test_scroll_region_and_insert_delete() {
+        let mut s = screen(3, 4);
+        s.process(b"1\r\n2\r\n3\r\n4\x1b[2;3r\x1b[3;1H\n\n");
+        assert_eq!(s.render(false), "1\n\n\n4\n");
+        s.process(b"\x1b[1;1Habc\x1b[1;2H\x1b[P");
+        assert_eq!(s.render(false), "ac\n\n\n4\n");
+        s.process(b"\x1b[2@");
+        assert_eq!(s.render(false), "a\n\n\n4\n");
+    }
+
+    #[test]
+    fn test_insert_characters() {
+        let mut s = screen(8, 1);
+        s.process(b"abcdef\x1b[1;2H\x1b[@");
+        assert_eq!(s.render(false), "a bcdef\n");
+
+        let mut s = screen(8, 1);
+        s.process(b"abc\x1b[1;2H\x1b[4hX");
+        assert_eq!(s.render(false), "aXbc\n");
+
+        // The wide character split by the insertion is removed
+        let mut s = screen(8, 1);
+        s.process("a日b\x1b[1;3H\x1b[@".as_bytes());
+        assert_eq!(s.render(false), "a   b\n");
+    }
+
+    #[test]
+    fn test_sgr_rendering() {
+        let mut s = screen(10, 1);
+        s.process(b"a\x1b[1;31mb\x1b[38;5;200mc\x1b[0md");
+        assert_eq!(s.render(false), "abcd\n");
+        assert_eq!(
+            s.render(true),
+            "a\x1b[1m\x1b[31mb\x1b[38;5;200mc\x1b[0m\x1b[39m\x1b[49md\n"
+        );
+    }
+
+    #[test]
+    fn test_alternate_screen_and_wide_chars() {
+        let mut s = screen(4, 2);
+        s.process(b"main\x1b[?1049h\x1b[H");
+        s.process("日本語".as_bytes());
+        assert_eq!(s.render(false), "日本語\n");
+        s.process(b"\x1b[?1049l");
+        assert_eq!(s.render(false), "main\n\n");
+    }
+
+    #[test]
+    fn test_line_drawing_and_resize() {
+        let mut s = screen(4, 3);
+        s.process(b"\x1b(0lqk\x1b(B\r\n\r\nend");
+        assert_eq!(s.render(false), "┌─┐\n\nend\n");
+        s.resize(TerminalSize {
+            cols: 2,
+            rows: 2,
+            xpixel: 0,
+            ypixel: 0,
+        });
+        assert_eq!(s.render(false), "\nen\n");
+    }
+}
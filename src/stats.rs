@@ -0,0 +1,144 @@
+use serde_json::json;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Summary statistics of a set of measured durations
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    pub median: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub stddev: Duration,
+}
+
+impl Summary {
+    /// Returns `None` if there are no samples
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+
+        let count = sorted.len();
+        let mean = sorted.iter().map(|d| d.as_secs_f64()).sum::<f64>() / count as f64;
+        let variance = sorted
+            .iter()
+            .map(|d| (d.as_secs_f64() - mean).powi(2))
+            .sum::<f64>()
+            / count as f64;
+
+        let median = if count.is_multiple_of(2) {
+            (sorted[count / 2 - 1] + sorted[count / 2]) / 2
+        } else {
+            sorted[count / 2]
+        };
+
+        Some(Self {
+            count,
+            min: sorted[0],
+            max: sorted[count - 1],
+            mean: Duration::from_secs_f64(mean),
+            median,
+            p90: percentile(&sorted, 90),
+            p99: percentile(&sorted, 99),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        })
+    }
+
+    /// Display the durations in automatically selected human units, instead of microseconds
+    pub fn human_units(&self) -> impl Display + '_ {
+        SummaryDisplay {
+            summary: self,
+            human_units: true,
+        }
+    }
+
+    pub fn microseconds(&self) -> impl Display + '_ {
+        SummaryDisplay {
+            summary: self,
+            human_units: false,
+        }
+    }
+
+    /// The durations are in microseconds
+    pub fn to_json(&self) -> serde_json::Value {
+        let us = |d: Duration| d.as_micros() as u64;
+        json!({
+            "count": self.count,
+            "min_us": us(self.min),
+            "max_us": us(self.max),
+            "mean_us": us(self.mean),
+            "median_us": us(self.median),
+            "p90_us": us(self.p90),
+            "p99_us": us(self.p99),
+            "stddev_us": us(self.stddev),
+        })
+    }
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100);
+    sorted[rank.max(1) - 1]
+}
+
+struct SummaryDisplay<'a> {
+    summary: &'a Summary,
+    human_units: bool,
+}
+
+impl Display for SummaryDisplay<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = self.summary;
+        writeln!(f, "count: {}", s.count)?;
+        for (name, value) in [
+            ("min", s.min),
+            ("max", s.max),
+            ("mean", s.mean),
+            ("median", s.median),
+            ("p90", s.p90),
+            ("p99", s.p99),
+            ("stddev", s.stddev),
+        ] {
+            if self.human_units {
+                writeln!(f, "{name}: {value:?}")?;
+            } else {
+                writeln!(f, "{name}: {}", value.as_micros())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::Summary;
+    use std::time::Duration;
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(Summary::from_samples(&[]), None);
+
+        let samples: Vec<Duration> = [4, 1, 3, 2, 10].map(Duration::from_micros).to_vec();
+        let summary = Summary::from_samples(&samples).unwrap();
+        assert_eq!(summary.count, 5);
+        assert_eq!(summary.min, Duration::from_micros(1));
+        assert_eq!(summary.max, Duration::from_micros(10));
+        assert_eq!(summary.mean, Duration::from_micros(4));
+        assert_eq!(summary.median, Duration::from_micros(3));
+        assert_eq!(summary.p90, Duration::from_micros(10));
+        assert_eq!(summary.p99, Duration::from_micros(10));
+        assert_eq!(summary.stddev.as_micros(), 3);
+
+        let samples: Vec<Duration> = (1..=100).map(Duration::from_micros).collect();
+        let summary = Summary::from_samples(&samples).unwrap();
+        assert_eq!(summary.median, Duration::from_nanos(50_500));
+        assert_eq!(summary.p90, Duration::from_micros(90));
+        assert_eq!(summary.p99, Duration::from_micros(99));
+    }
+}
@@ -0,0 +1,72 @@
+use crate::file_format::TerminalSize;
+use anyhow::Context;
+use nix::errno::Errno;
+use nix::libc;
+use nix::pty::Winsize;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use std::io::stdin;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+
+impl From<TerminalSize> for Winsize {
+    fn from(size: TerminalSize) -> Self {
+        Winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: size.xpixel,
+            ws_ypixel: size.ypixel,
+        }
+    }
+}
+
+impl From<Winsize> for TerminalSize {
+    fn from(winsize: Winsize) -> Self {
+        TerminalSize {
+            cols: winsize.ws_col,
+            rows: winsize.ws_row,
+            xpixel: winsize.ws_xpixel,
+            ypixel: winsize.ws_ypixel,
+        }
+    }
+}
+
+pub fn get_terminal_size(fd: BorrowedFd) -> nix::Result<TerminalSize> {
+    let mut winsize = MaybeUninit::<Winsize>::uninit();
+    // SAFETY: TIOCGWINSZ writes a Winsize struct to the pointer, which points to valid memory
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCGWINSZ, winsize.as_mut_ptr()) };
+    Errno::result(ret)?;
+    // SAFETY: The ioctl succeeded, so the struct was initialized
+    Ok(unsafe { winsize.assume_init() }.into())
+}
+
+pub fn set_terminal_size(fd: BorrowedFd, size: TerminalSize) -> nix::Result<()> {
+    let winsize = Winsize::from(size);
+    // SAFETY: TIOCSWINSZ only reads the Winsize struct, which is valid for the duration of the call
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCSWINSZ, &winsize) };
+    Errno::result(ret)?;
+    Ok(())
+}
+
+/// Puts the terminal connected to stdin into raw mode, the original mode is restored on drop
+pub struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    pub fn enable() -> anyhow::Result<Self> {
+        let stdin = stdin();
+        let original = tcgetattr(stdin.as_fd()).context("stdin is not a terminal")?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &raw).context("Failed to enable raw mode")?;
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Err(e) = tcsetattr(stdin().as_fd(), SetArg::TCSANOW, &self.original) {
+            log::error!("Failed to restore terminal mode: {e}");
+        }
+    }
+}
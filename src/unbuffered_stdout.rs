@@ -19,8 +19,14 @@ impl UnbufferedStdout {
         let out = ManuallyDrop::new(unsafe { File::from_raw_fd(lock.as_raw_fd()) });
         Self { lock, out }
     }
+}
+
+impl Write for UnbufferedStdout {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.out.write(buf)
+    }
 
-    pub fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        self.out.write_all(buf)
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
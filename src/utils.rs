@@ -1,6 +1,8 @@
+use anyhow::{bail, Context};
 use nix::libc::memmem;
 use std::borrow::Cow;
 use std::ffi::c_void;
+use std::time::Duration;
 
 pub fn find_subslice(heysstack: &[u8], needle: &[u8]) -> Option<usize> {
     if heysstack.is_empty() {
@@ -45,9 +47,97 @@ pub fn delete_subslices<'a>(data: &'a [u8], delete_sequences: &[&[u8]]) -> Cow<'
     Cow::Owned(data)
 }
 
+/// 64-bit FNV-1a hash, unlike the std hashers it is guaranteed to be stable
+pub fn fnv1a_64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A small pseudo-random number generator (xorshift64*), for when the numbers don't need to be
+/// unpredictable, but reproducible
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // The state must not be zero
+        XorShift(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// A number in the range [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Parses a duration such as `1.5s`, `250ms`, `100us`, `2m` or `1h30m`. A number without a unit
+/// is in seconds.
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    if s.is_empty() {
+        bail!("Empty duration");
+    }
+    if let Ok(seconds) = s.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds)
+            .with_context(|| format!("Invalid duration: {s}"));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let unit_len = rest[number_len..]
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len() - number_len);
+        let (number, unit) = (
+            &rest[..number_len],
+            &rest[number_len..number_len + unit_len],
+        );
+        let number: f64 = number
+            .parse()
+            .with_context(|| format!("Invalid duration: {s}"))?;
+        let unit_seconds = match unit {
+            "us" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => bail!("Invalid duration unit {unit:?} in {s} (expected us, ms, s, m or h)"),
+        };
+        total += Duration::try_from_secs_f64(number * unit_seconds)
+            .with_context(|| format!("Invalid duration: {s}"))?;
+        rest = &rest[number_len + unit_len..];
+    }
+    Ok(total)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::utils::delete_subslices;
+    use crate::utils::{delete_subslices, parse_duration};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_duration("0.5").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("100us").unwrap(), Duration::from_micros(100));
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("1h2m3s").unwrap(), Duration::from_secs(3723));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("-1").is_err());
+        assert!(parse_duration("5x").is_err());
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("1..5s").is_err());
+    }
 
     #[test]
     fn test_delete_subslices() {